@@ -0,0 +1,144 @@
+//! Benchmarks of the group picture pipeline
+//!
+//! Run with `cargo bench`. Save a baseline with `cargo bench -- --save-baseline main` before a
+//! change, and compare against it with `cargo bench -- --baseline main` after.
+
+use std::{fs, path::PathBuf};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use groupic::gen_pic::{self, GroupPicConfig, Layout};
+use image::{ImageBuffer, Rgba, RgbaImage};
+
+/// Numbers of members from a small call to a large stage event
+const AVATAR_COUNTS: [usize; 4] = [1, 25, 99, 500];
+const TILE_SIZES: [u32; 3] = [64, 128, 256];
+const HEADER_TEXT: &str = "niji3rd-live-day1";
+
+/// Avatars split into two colors along a diagonal, like a simple avatar with an edge to resample
+fn test_avatars(n: usize, size: u32) -> Vec<RgbaImage> {
+    (0..n)
+        .map(|i| {
+            let i = i as u32;
+            let above = Rgba([(i * 37 % 256) as u8, (i * 91 % 256) as u8, 200, 255]);
+            let below = Rgba([200, (i * 53 % 256) as u8, (i * 17 % 256) as u8, 255]);
+            ImageBuffer::from_fn(size, size, |x, y| if x > y { above } else { below })
+        })
+        .collect()
+}
+
+/// Directory with a directory of avatar files in it, removed when dropped
+struct AvatarDir(PathBuf);
+
+impl AvatarDir {
+    fn new(n: usize) -> Self {
+        let dir = std::env::temp_dir().join(format!("groupic-bench-{}-{}", n, std::process::id()));
+        let dir = Self(dir);
+        fs::create_dir_all(dir.avatars()).unwrap();
+        for (i, avatar) in test_avatars(n, 128).iter().enumerate() {
+            avatar
+                .save(dir.avatars().join(format!("{:03}.png", i)))
+                .unwrap();
+        }
+        dir
+    }
+
+    /// Directory of the avatar files alone, so that the group picture can be written beside it
+    fn avatars(&self) -> PathBuf {
+        self.0.join("avatars")
+    }
+}
+
+impl Drop for AvatarDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Decoding the avatar files, rendering and encoding the PNG
+fn generate_group_pic(c: &mut Criterion) {
+    let mut group = c.benchmark_group("generate_group_pic");
+    group.sample_size(10);
+    for n in AVATAR_COUNTS {
+        let dir = AvatarDir::new(n);
+        let (avatars, out) = (dir.avatars(), dir.0.join("group_pic.png"));
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, _| {
+            b.iter(|| gen_pic::generate_group_pic(&avatars, &out, None, HEADER_TEXT).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Rendering decoded avatars downloaded at the tile size
+fn render_group_pic(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render_group_pic");
+    group.sample_size(10);
+    for tile_size in TILE_SIZES {
+        for n in AVATAR_COUNTS {
+            let avatars = test_avatars(n, tile_size);
+            let config = GroupPicConfig {
+                header_text: HEADER_TEXT.into(),
+                tile_size,
+                ..Default::default()
+            };
+            group.throughput(Throughput::Elements(n as u64));
+            let id = BenchmarkId::new(format!("{}px", tile_size), n);
+            group.bench_with_input(id, &avatars, |b, avatars| {
+                b.iter(|| gen_pic::render_group_pic(avatars, &config).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Rendering the same avatars in each layout, with names under them
+fn render_layouts(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render_layouts");
+    group.sample_size(10);
+    let avatars = test_avatars(99, 128);
+    for layout in Layout::ALL {
+        let config = GroupPicConfig {
+            header_text: HEADER_TEXT.into(),
+            layout,
+            captions: Some((0..99).map(|i| format!("Member {}", i)).collect()),
+            ..Default::default()
+        };
+        group.bench_function(layout.as_ref(), |b| {
+            b.iter(|| gen_pic::render_group_pic(&avatars, &config).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Laying out and drawing the header alone
+fn render_header(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render_header");
+    let headers = [
+        ("short", HEADER_TEXT),
+        ("kana-kanji", "ラブライブ!虹ヶ咲3rdライブ1日目"),
+        ("emoji", "🔥👀🌾🍛 oats curry"),
+        (
+            "wrapped",
+            "Love Live! Nijigasaki High School Idol Club 3rd Live School Idol Festival",
+        ),
+    ];
+    for (name, header_text) in headers {
+        let config = GroupPicConfig {
+            header_text: header_text.into(),
+            ..Default::default()
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| gen_pic::render_header(&config, 128 * 5).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    generate_group_pic,
+    render_group_pic,
+    render_layouts,
+    render_header
+);
+criterion_main!(benches);
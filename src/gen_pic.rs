@@ -1,233 +1,3234 @@
 #![allow(dead_code)]
 
-use std::{fs, path::Path};
+use std::{borrow::Cow, error::Error, fmt, fs, io, io::Cursor, path::Path, str::FromStr};
 
-use glyph_brush_layout::{
-    ab_glyph::{Font, FontRef, PxScale, ScaleFont},
-    FontId, GlyphPositioner, Layout, SectionGeometry, SectionGlyph, SectionText,
+use glyph_brush_layout::ab_glyph::InvalidFont;
+use image::{
+    codecs::gif::{GifDecoder, GifEncoder, Repeat},
+    imageops::{blur, crop_imm, resize},
+    AnimationDecoder, Delay, Frame, GrayImage, ImageBuffer, ImageError, ImageFormat,
+    ImageOutputFormat, Luma, Pixel, Rgba, RgbaImage,
 };
-use image::{imageops::resize, GenericImage, ImageBuffer, Pixel, Rgba, RgbaImage};
-use num::{integer::Roots, Integer};
+use num::Integer;
+use rayon::prelude::*;
 
-const FONT_DATA: &[u8] = include_bytes!("../NotoSansJP-Medium.otf");
-const EMOJI_FONT_DATA: &[u8] = include_bytes!("../NotoColorEmoji.ttf");
-const DISCORD_COLOR: Rgba<u8> = Rgba([48, 48, 54, 255]);
+mod layout;
+pub mod template;
+pub mod text;
 
+pub use layout::Layout;
+use layout::{HeaderPlacement, TileMetrics};
+use text::{Align, Clip, Fonts, ShapedText};
+pub use text::{Shadow, Stroke, TextStyle};
+
+/// Horizontal space kept clear on both sides of the header text
+const HEADER_MARGIN: u32 = 8;
+/// Horizontal space kept clear on both sides of a caption, and below it
+const CAPTION_MARGIN: u32 = 4;
+/// Width and height of each avatar in pixels that can be requested from the Discord CDN
+pub const TILE_SIZES: [u32; 4] = [64, 128, 256, 512];
+/// Diameter of a voice state badge on a 128 pixel tile
+const BADGE_SIZE: u32 = 24;
+/// Width of the ring in the background color around each badge on a 128 pixel tile
+const BADGE_BORDER: u32 = 2;
+/// Attachment size limit of Discord without boosts in bytes
+pub const DISCORD_UPLOAD_LIMIT: usize = 8 * 1024 * 1024;
+/// Quality of lossy encodings unless given otherwise
+const DEFAULT_QUALITY: u8 = 90;
+/// Samples per pixel along each axis when rendering the coverage of a shape
+const COVERAGE_SAMPLES: u32 = 4;
+/// Shortest delay between frames of an animated group picture in milliseconds
+const MIN_FRAME_DELAY_MS: u32 = 20;
+
+/// Errors that can occur while generating a group picture
+#[derive(Debug)]
+pub enum GenPicError {
+    /// There is no avatar to put in the group picture
+    NoAvatars,
+    /// The number of avatars in a row must be at least 1
+    ZeroColumns,
+    /// There must be one caption for each avatar
+    CaptionCountMismatch { avatars: usize, captions: usize },
+    /// The name is not one of the layouts
+    UnknownLayout(String),
+    /// Both sides of the aspect ratio must be at least 1
+    InvalidAspectRatio(u32, u32),
+    /// The tile size is not one of `TILE_SIZES`
+    InvalidTileSize(u32),
+    /// There must be one set of voice state badges for each avatar
+    BadgeCountMismatch { avatars: usize, badges: usize },
+    /// There must be one ring color for each avatar
+    RingCountMismatch { avatars: usize, rings: usize },
+    /// The sections must add up to the avatars
+    SectionCountMismatch { avatars: usize, sections: usize },
+    /// Every avatar of an animated group picture must have at least one frame
+    NoFrames { avatar: usize },
+    /// The encoded group picture does not fit the byte budget however it is encoded
+    OverBudget { bytes: usize, max_bytes: usize },
+    /// The name is not one of the output formats
+    UnknownOutputFormat(String),
+    /// The name is not one of the built-in themes
+    UnknownTheme(String),
+    /// The name is not one of the text effects
+    UnknownTextEffect(String),
+    /// The name is not one of the overlay anchors
+    UnknownOverlayAnchor(String),
+    /// The color is not written like `#5865f2` or `#5865f280`
+    InvalidColor(String),
+    /// The template could not be parsed
+    InvalidTemplate(String),
+    /// The name is not one of the mask shapes
+    UnknownMaskShape(String),
+    /// The embedded font could not be loaded
+    InvalidFont(InvalidFont),
+    /// Failed to read the avatars or write the group picture
+    Io(io::Error),
+    /// Failed to decode an avatar or encode the group picture
+    Image(ImageError),
+}
+
+impl fmt::Display for GenPicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoAvatars => write!(f, "no avatar to put in the group picture"),
+            Self::ZeroColumns => write!(f, "number of avatars in a row must be at least 1"),
+            Self::CaptionCountMismatch { avatars, captions } => write!(
+                f,
+                "{} captions given for {} avatars, expected one for each",
+                captions, avatars
+            ),
+            Self::BadgeCountMismatch { avatars, badges } => write!(
+                f,
+                "{} sets of badges given for {} avatars, expected one for each",
+                badges, avatars
+            ),
+            Self::RingCountMismatch { avatars, rings } => write!(
+                f,
+                "{} ring colors given for {} avatars, expected one for each",
+                rings, avatars
+            ),
+            Self::SectionCountMismatch { avatars, sections } => write!(
+                f,
+                "sections hold {} avatars in total, but {} avatars are given",
+                sections, avatars
+            ),
+            Self::UnknownLayout(name) => write!(f, "unknown layout {}", name),
+            Self::InvalidAspectRatio(w, h) => write!(f, "invalid aspect ratio {}:{}", w, h),
+            Self::InvalidTileSize(size) => {
+                write!(f, "tile size {} is not one of {:?}", size, TILE_SIZES)
+            }
+            Self::NoFrames { avatar } => write!(f, "avatar {} has no frame", avatar),
+            Self::OverBudget { bytes, max_bytes } => write!(
+                f,
+                "group picture takes at least {} bytes, more than the budget of {}",
+                bytes, max_bytes
+            ),
+            Self::UnknownOutputFormat(name) => write!(f, "unknown output format {}", name),
+            Self::UnknownTheme(name) => write!(f, "unknown theme {}", name),
+            Self::UnknownTextEffect(name) => write!(f, "unknown text effect {}", name),
+            Self::UnknownOverlayAnchor(name) => write!(f, "unknown overlay anchor {}", name),
+            Self::InvalidColor(color) => {
+                write!(f, "expected a color like #5865f2 but got {}", color)
+            }
+            Self::InvalidTemplate(msg) => write!(f, "invalid template: {}", msg),
+            Self::UnknownMaskShape(name) => write!(f, "unknown mask shape {}", name),
+            Self::InvalidFont(e) => write!(f, "failed to load font: {}", e),
+            Self::Io(e) => write!(f, "failed to read or write image file: {}", e),
+            Self::Image(e) => write!(f, "failed to process image: {}", e),
+        }
+    }
+}
+
+impl Error for GenPicError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidFont(e) => Some(e),
+            Self::Io(e) => Some(e),
+            Self::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<InvalidFont> for GenPicError {
+    fn from(e: InvalidFont) -> Self {
+        Self::InvalidFont(e)
+    }
+}
+
+impl From<io::Error> for GenPicError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ImageError> for GenPicError {
+    fn from(e: ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+
+/// Parse a color like `#5865f2`, or `#5865f280` with alpha
+pub fn parse_color(s: &str) -> Result<Rgba<u8>, GenPicError> {
+    let hex = s.trim().trim_start_matches('#');
+    let invalid = || GenPicError::InvalidColor(s.to_owned());
+    if !matches!(hex.len(), 6 | 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    let alpha = if hex.len() == 8 { byte(6)? } else { 255 };
+    Ok(Rgba([byte(0)?, byte(2)?, byte(4)?, alpha]))
+}
+
+/// Colors and spacing of the group picture
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroupPicStyle {
+    /// Color behind the avatars, also filling the masked out corners of each avatar
+    pub background_color: Rgba<u8>,
+    /// Color behind the header text
+    pub header_color: Rgba<u8>,
+    /// Color of the header text and the captions
+    pub text_color: Rgba<u8>,
+    /// Space between the avatars and the left, right and bottom edges in pixels
+    pub padding: u32,
+    /// Space between adjacent avatars in pixels
+    pub tile_gap: u32,
+    /// Outline around the header text, the sub-headers and the captions
+    pub text_stroke: Option<Stroke>,
+    /// Shadow under the header text, the sub-headers and the captions
+    pub text_shadow: Option<Shadow>,
+    /// Color of a bar behind the header text, usually translucent
+    pub header_bar: Option<Rgba<u8>>,
+}
+
+impl GroupPicStyle {
+    pub const DISCORD_DARK: Self = Self {
+        background_color: Rgba([48, 48, 54, 255]),
+        header_color: Rgba([48, 48, 54, 255]),
+        text_color: Rgba([240, 240, 240, 255]),
+        padding: 0,
+        tile_gap: 0,
+        text_stroke: None,
+        text_shadow: None,
+        header_bar: None,
+    };
+    pub const DISCORD_LIGHT: Self = Self {
+        background_color: Rgba([255, 255, 255, 255]),
+        header_color: Rgba([242, 243, 245, 255]),
+        text_color: Rgba([6, 6, 7, 255]),
+        padding: 8,
+        tile_gap: 8,
+        text_stroke: None,
+        text_shadow: None,
+        header_bar: None,
+    };
+    pub const AMOLED_BLACK: Self = Self {
+        background_color: Rgba([0, 0, 0, 255]),
+        header_color: Rgba([0, 0, 0, 255]),
+        text_color: Rgba([255, 255, 255, 255]),
+        padding: 8,
+        tile_gap: 8,
+        text_stroke: None,
+        text_shadow: None,
+        header_bar: None,
+    };
+    pub const TRANSPARENT: Self = Self {
+        background_color: Rgba([0, 0, 0, 0]),
+        header_color: Rgba([0, 0, 0, 0]),
+        text_color: Rgba([240, 240, 240, 255]),
+        padding: 0,
+        tile_gap: 8,
+        text_stroke: None,
+        text_shadow: None,
+        header_bar: None,
+    };
+}
+
+impl GroupPicStyle {
+    /// Turn on `effect` for the text, in a color contrasting with the text color
+    pub fn with_text_effect(self, effect: TextEffect) -> Self {
+        let [r, g, b, _] = self.text_color.0;
+        let luma = (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000;
+        let contrast = |alpha: u8| {
+            if luma > 127 {
+                Rgba([0, 0, 0, alpha])
+            } else {
+                Rgba([255, 255, 255, alpha])
+            }
+        };
+        match effect {
+            TextEffect::None => Self {
+                text_stroke: None,
+                text_shadow: None,
+                header_bar: None,
+                ..self
+            },
+            TextEffect::Stroke => Self {
+                text_stroke: Some(Stroke {
+                    width: 2.,
+                    color: contrast(255),
+                }),
+                ..self
+            },
+            TextEffect::Shadow => Self {
+                text_shadow: Some(Shadow {
+                    offset: (2., 2.),
+                    blur: 2.,
+                    color: contrast(192),
+                }),
+                ..self
+            },
+            TextEffect::Bar => Self {
+                header_bar: Some(contrast(128)),
+                ..self
+            },
+        }
+    }
+
+    /// Style of text in `color` at `font_size` with the text effects of this style
+    fn text_style(&self, font_size: f32, color: Rgba<u8>) -> TextStyle {
+        TextStyle {
+            stroke: self.text_stroke,
+            shadow: self.text_shadow,
+            ..TextStyle::new(font_size, color)
+        }
+    }
+}
+
+impl Default for GroupPicStyle {
+    fn default() -> Self {
+        Self::DISCORD_DARK
+    }
+}
+
+/// Effect which keeps the text readable over background images
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TextEffect {
+    #[default]
+    None,
+    /// Outline around the glyphs
+    Stroke,
+    /// Soft shadow down and to the right of the glyphs
+    Shadow,
+    /// Translucent bar behind the header text
+    Bar,
+}
+
+impl TextEffect {
+    pub const ALL: [TextEffect; 4] = [
+        TextEffect::None,
+        TextEffect::Stroke,
+        TextEffect::Shadow,
+        TextEffect::Bar,
+    ];
+}
+
+impl AsRef<str> for TextEffect {
+    fn as_ref(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Stroke => "stroke",
+            Self::Shadow => "shadow",
+            Self::Bar => "bar",
+        }
+    }
+}
+
+impl FromStr for TextEffect {
+    type Err = GenPicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|effect| effect.as_ref() == s)
+            .copied()
+            .ok_or_else(|| GenPicError::UnknownTextEffect(s.to_owned()))
+    }
+}
+
+/// Built-in presets of `GroupPicStyle`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Theme {
+    DiscordDark,
+    DiscordLight,
+    AmoledBlack,
+    Transparent,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 4] = [
+        Theme::DiscordDark,
+        Theme::DiscordLight,
+        Theme::AmoledBlack,
+        Theme::Transparent,
+    ];
+
+    pub fn style(&self) -> GroupPicStyle {
+        match self {
+            Self::DiscordDark => GroupPicStyle::DISCORD_DARK,
+            Self::DiscordLight => GroupPicStyle::DISCORD_LIGHT,
+            Self::AmoledBlack => GroupPicStyle::AMOLED_BLACK,
+            Self::Transparent => GroupPicStyle::TRANSPARENT,
+        }
+    }
+}
+
+impl AsRef<str> for Theme {
+    fn as_ref(&self) -> &'static str {
+        match self {
+            Self::DiscordDark => "discord-dark",
+            Self::DiscordLight => "discord-light",
+            Self::AmoledBlack => "amoled-black",
+            Self::Transparent => "transparent",
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = GenPicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|theme| theme.as_ref() == s)
+            .copied()
+            .ok_or_else(|| GenPicError::UnknownTheme(s.to_owned()))
+    }
+}
+
+/// Shape each avatar is cut into
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum MaskShape {
+    #[default]
+    Circle,
+    RoundedSquare,
+    Squircle,
+    Hexagon,
+    Heart,
+}
+
+impl MaskShape {
+    pub const ALL: [MaskShape; 5] = [
+        MaskShape::Circle,
+        MaskShape::RoundedSquare,
+        MaskShape::Squircle,
+        MaskShape::Hexagon,
+        MaskShape::Heart,
+    ];
+
+    /// Whether the point `(u, v)` is inside the shape, where the shape fits in the square from
+    /// `(-1, -1)` to `(1, 1)` and `v` points downwards
+    fn contains(&self, u: f32, v: f32) -> bool {
+        match self {
+            Self::Circle => u * u + v * v <= 1.,
+            Self::RoundedSquare => {
+                let r = 0.4;
+                let dx = (u.abs() - (1. - r)).max(0.);
+                let dy = (v.abs() - (1. - r)).max(0.);
+                u.abs() <= 1. && v.abs() <= 1. && dx * dx + dy * dy <= r * r
+            }
+            Self::Squircle => u.powi(4) + v.powi(4) <= 1.,
+            // pointy top hexagon with a circumradius of 1
+            Self::Hexagon => {
+                let sqrt3 = 3f32.sqrt();
+                u.abs() <= sqrt3 / 2. && v.abs() + u.abs() / sqrt3 <= 1.
+            }
+            // (x² + y² - 1)³ ≤ x²y³, which spans about ±1.14 horizontally and -1 to 1.24
+            // vertically, so it is scaled down and moved to the center
+            Self::Heart => {
+                let x = u * 1.14;
+                let y = -v * 1.14 + 0.12;
+                (x * x + y * y - 1.).powi(3) <= x * x * y.powi(3)
+            }
+        }
+    }
+
+    /// Whether the shape fits in the circle inscribed in its square, so that rows of it can
+    /// nestle into each other
+    fn fits_in_circle(&self) -> bool {
+        matches!(self, Self::Circle | Self::Hexagon)
+    }
+}
+
+impl AsRef<str> for MaskShape {
+    fn as_ref(&self) -> &'static str {
+        match self {
+            Self::Circle => "circle",
+            Self::RoundedSquare => "rounded-square",
+            Self::Squircle => "squircle",
+            Self::Hexagon => "hexagon",
+            Self::Heart => "heart",
+        }
+    }
+}
+
+impl FromStr for MaskShape {
+    type Err = GenPicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|shape| shape.as_ref() == s)
+            .copied()
+            .ok_or_else(|| GenPicError::UnknownMaskShape(s.to_owned()))
+    }
+}
+
+/// Voice state of a member, shown as badges at the bottom right corner of their avatar
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct VoiceBadges {
+    /// Muted by themselves
+    pub self_mute: bool,
+    /// Deafened by themselves, shown instead of `self_mute`
+    pub self_deaf: bool,
+    /// Muted by the server
+    pub server_mute: bool,
+    /// Streaming with Go Live
+    pub streaming: bool,
+    /// Camera turned on
+    pub video: bool,
+}
+
+impl VoiceBadges {
+    /// Badges to draw, from right to left
+    fn badges(&self) -> Vec<Badge> {
+        let mut badges = Vec::new();
+        if self.self_deaf {
+            badges.push(Badge::SelfDeaf);
+        } else if self.self_mute {
+            badges.push(Badge::SelfMute);
+        }
+        if self.server_mute {
+            badges.push(Badge::ServerMute);
+        }
+        if self.video {
+            badges.push(Badge::Video);
+        }
+        if self.streaming {
+            badges.push(Badge::Streaming);
+        }
+        badges
+    }
+}
+
+/// Single badge drawn on an avatar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Badge {
+    SelfMute,
+    SelfDeaf,
+    ServerMute,
+    Video,
+    Streaming,
+}
+
+impl Badge {
+    /// Width of the badge of height `size`, the streaming badge being a pill with "LIVE" in it
+    fn width(&self, size: u32) -> u32 {
+        match self {
+            Self::Streaming => 2 * size,
+            _ => size,
+        }
+    }
+
+    fn color(&self) -> Rgba<u8> {
+        match self {
+            Self::ServerMute | Self::Streaming => Rgba([237, 66, 69, 255]),
+            _ => Rgba([79, 84, 92, 255]),
+        }
+    }
+
+    /// Whether the point `(u, v)` is inside the icon, where the icon fits in the square from
+    /// `(-1, -1)` to `(1, 1)` and `v` points downwards
+    fn icon_contains(&self, u: f32, v: f32) -> bool {
+        match self {
+            Self::SelfMute | Self::ServerMute => slashed(u, v, microphone),
+            Self::SelfDeaf => slashed(u, v, headphones),
+            Self::Video => camera(u, v),
+            Self::Streaming => false,
+        }
+    }
+}
+
+fn distance_to_segment(u: f32, v: f32, (u0, v0): (f32, f32), (u1, v1): (f32, f32)) -> f32 {
+    let (du, dv) = (u1 - u0, v1 - v0);
+    let len2 = du * du + dv * dv;
+    let t = if len2 > 0. {
+        (((u - u0) * du + (v - v0) * dv) / len2).clamp(0., 1.)
+    } else {
+        0.
+    };
+    (u - u0 - t * du).hypot(v - v0 - t * dv)
+}
+
+/// Cross out `icon` with a diagonal line, leaving a gap around the line
+fn slashed(u: f32, v: f32, icon: fn(f32, f32) -> bool) -> bool {
+    let d = distance_to_segment(u, v, (-0.85, -0.85), (0.85, 0.85));
+    d <= 0.12 || (d >= 0.27 && icon(u, v))
+}
+
+fn microphone(u: f32, v: f32) -> bool {
+    // capsule, the lower half of a ring holding it, and the stand
+    distance_to_segment(u, v, (0., -0.55), (0., -0.05)) <= 0.32
+        || (v >= -0.05 && (u.hypot(v + 0.05) - 0.6).abs() <= 0.11)
+        || (u.abs() <= 0.11 && (0.5..=0.9).contains(&v))
+}
+
+fn headphones(u: f32, v: f32) -> bool {
+    // upper half of a ring as the band, and the ear cups
+    (v <= 0.1 && (u.hypot(v - 0.1) - 0.72).abs() <= 0.12)
+        || ((u.abs() - 0.62).abs() <= 0.22 && (0.05..=0.8).contains(&v))
+}
+
+fn camera(u: f32, v: f32) -> bool {
+    // body, and the lens widening to the right
+    ((-0.85..=0.3).contains(&u) && v.abs() <= 0.5)
+        || ((0.4..=0.9).contains(&u) && v.abs() <= 0.15 + (u - 0.4) * 0.6)
+}
+
+/// Encoding of the group picture
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    Png,
+    WebPLossless,
+    /// Lossy WebP with a quality from 0 to 100
+    WebP(u8),
+    /// JPEG with a quality from 1 to 100, dropping the alpha channel
+    Jpeg(u8),
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 4] = [
+        OutputFormat::Png,
+        OutputFormat::WebPLossless,
+        OutputFormat::WebP(DEFAULT_QUALITY),
+        OutputFormat::Jpeg(DEFAULT_QUALITY),
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::WebPLossless | Self::WebP(_) => "webp",
+            Self::Jpeg(_) => "jpg",
+        }
+    }
+}
+
+impl AsRef<str> for OutputFormat {
+    fn as_ref(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::WebPLossless => "webp-lossless",
+            Self::WebP(_) => "webp",
+            Self::Jpeg(_) => "jpeg",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = GenPicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|format| format.as_ref() == s)
+            .copied()
+            .ok_or_else(|| GenPicError::UnknownOutputFormat(s.to_owned()))
+    }
+}
+
+/// Image drawn behind the group picture instead of the flat background color
+#[derive(Debug, Clone)]
+pub struct Background {
+    /// Image scaled to cover the whole group picture, cropped around its center
+    pub image: RgbaImage,
+    /// Standard deviation of the Gaussian blur in pixels, or no blur if 0
+    pub blur: f32,
+    /// How far the image is faded towards the background color of the style, from 0 to 1
+    pub dim: f32,
+}
+
+impl Background {
+    /// Blur and dim `image` enough that the avatars and the text stand out
+    pub fn new(image: RgbaImage) -> Self {
+        Self {
+            image,
+            blur: 8.,
+            dim: 0.5,
+        }
+    }
+}
+
+/// Where an overlay is placed on the group picture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum OverlayAnchor {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    #[default]
+    Center,
+    /// Stretched over the whole group picture, such as a frame
+    Fill,
+}
+
+impl OverlayAnchor {
+    pub const ALL: [OverlayAnchor; 6] = [
+        OverlayAnchor::TopLeft,
+        OverlayAnchor::TopRight,
+        OverlayAnchor::BottomLeft,
+        OverlayAnchor::BottomRight,
+        OverlayAnchor::Center,
+        OverlayAnchor::Fill,
+    ];
+}
+
+impl AsRef<str> for OverlayAnchor {
+    fn as_ref(&self) -> &'static str {
+        match self {
+            Self::TopLeft => "top-left",
+            Self::TopRight => "top-right",
+            Self::BottomLeft => "bottom-left",
+            Self::BottomRight => "bottom-right",
+            Self::Center => "center",
+            Self::Fill => "fill",
+        }
+    }
+}
+
+impl FromStr for OverlayAnchor {
+    type Err = GenPicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|anchor| anchor.as_ref() == s)
+            .copied()
+            .ok_or_else(|| GenPicError::UnknownOverlayAnchor(s.to_owned()))
+    }
+}
+
+/// Image drawn over the finished group picture, such as a seasonal decoration or a logo
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    pub image: RgbaImage,
+    pub anchor: OverlayAnchor,
+    /// Space kept between the image and the edges it is placed against in pixels
+    pub margin: u32,
+    /// Opacity of the image, from 0 to 1
+    pub opacity: f32,
+}
+
+impl Overlay {
+    /// Draw `image` as it is at the center
+    pub fn new(image: RgbaImage) -> Self {
+        Self {
+            image,
+            anchor: OverlayAnchor::default(),
+            margin: 0,
+            opacity: 1.,
+        }
+    }
+}
+
+/// Run of consecutive avatars laid out on its own below a sub-header
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvatarSection {
+    /// Text of the sub-header
+    pub title: String,
+    /// Color of the sub-header text, or the text color of the style if `None`
+    pub color: Option<Rgba<u8>>,
+    /// Number of avatars in the section
+    pub count: usize,
+}
+
+/// Configuration of the group picture
+#[derive(Debug, Clone)]
+pub struct GroupPicConfig {
+    /// Text rendered in the header, usually the name of the voice channel
+    pub header_text: String,
+    /// Height of the header in pixels, grown by a line height for every extra line of text
+    pub header_h: u32,
+    /// Font size of the header text in pixels
+    pub header_font_size: f32,
+    /// Font size the header text may shrink to before it wraps onto more lines
+    pub header_min_font_size: f32,
+    /// Number of lines the header text may wrap onto before it is ellipsized
+    pub header_max_lines: u32,
+    /// Image cut into a circle left of the header text, such as the guild icon
+    pub header_icon: Option<RgbaImage>,
+    /// Line of smaller text under the header text, or no subtitle if `None`
+    pub subtitle: Option<String>,
+    /// Font size of the subtitle in pixels
+    pub subtitle_font_size: f32,
+    /// Width and height of each avatar in pixels, one of `TILE_SIZES`
+    pub tile_size: u32,
+    /// Arrangement of the avatars
+    pub layout: Layout,
+    /// Number of avatars in a row for the layouts made of rows, or roughly square if `None`
+    pub num_of_avatars_in_a_row: Option<u32>,
+    /// Width to height ratio of `Layout::AspectRatioGrid`
+    pub aspect_ratio: (u32, u32),
+    /// Caption under each avatar in the same order as the avatars, or no captions if `None`
+    pub captions: Option<Vec<String>>,
+    /// Font size of the captions in pixels
+    pub caption_font_size: f32,
+    /// Voice state badges of each avatar in the same order as the avatars, or no badges if `None`
+    pub badges: Option<Vec<VoiceBadges>>,
+    /// Color of the ring around each avatar in the same order as the avatars, or no rings if
+    /// `None`
+    pub rings: Option<Vec<Rgba<u8>>>,
+    /// Width of the rings in pixels
+    pub ring_thickness: u32,
+    /// Samples per pixel along each axis on the rings, where 1 turns anti-aliasing off
+    pub ring_antialiasing: u32,
+    /// Sections the avatars are grouped into in order, or a single group without a sub-header if
+    /// `None`
+    pub sections: Option<Vec<AvatarSection>>,
+    /// Font size of the section sub-headers in pixels
+    pub section_font_size: f32,
+    /// Image behind the avatars, or the flat background color of the style if `None`
+    pub background: Option<Background>,
+    /// Colors and spacing
+    pub style: GroupPicStyle,
+    /// Shape each avatar is cut into
+    pub mask_shape: MaskShape,
+    /// Leave the background, the header and the masked out areas fully transparent, whatever
+    /// colors the style has
+    pub transparent: bool,
+    /// Line of text in a bar under the avatars, or no footer if `None`
+    pub footer: Option<String>,
+    /// Font size of the footer in pixels
+    pub footer_font_size: f32,
+    /// Images drawn over the finished group picture in order
+    pub overlays: Vec<Overlay>,
+}
+
+impl Default for GroupPicConfig {
+    fn default() -> Self {
+        Self {
+            header_text: String::new(),
+            header_h: 64,
+            header_font_size: 54.,
+            header_min_font_size: 32.,
+            header_max_lines: 3,
+            header_icon: None,
+            subtitle: None,
+            subtitle_font_size: 24.,
+            tile_size: 128,
+            layout: Layout::default(),
+            num_of_avatars_in_a_row: None,
+            aspect_ratio: (16, 9),
+            captions: None,
+            caption_font_size: 18.,
+            badges: None,
+            rings: None,
+            ring_thickness: 4,
+            ring_antialiasing: COVERAGE_SAMPLES,
+            sections: None,
+            section_font_size: 28.,
+            background: None,
+            style: GroupPicStyle::default(),
+            mask_shape: MaskShape::default(),
+            transparent: false,
+            footer: None,
+            footer_font_size: 18.,
+            overlays: vec![],
+        }
+    }
+}
+
+/// Limits on an animated group picture, so that it can still be uploaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnimationBudget {
+    /// Maximum number of frames in one loop
+    pub max_frames: u32,
+    /// Maximum length of one loop in milliseconds, cutting longer animations short
+    pub max_duration_ms: u32,
+    /// Maximum size of the encoded GIF in bytes
+    pub max_bytes: usize,
+}
+
+impl Default for AnimationBudget {
+    fn default() -> Self {
+        Self {
+            max_frames: 50,
+            max_duration_ms: 10_000,
+            max_bytes: DISCORD_UPLOAD_LIMIT,
+        }
+    }
+}
+
+/// Read every avatar in `avatars_dir` in the order of their paths and save the group picture to
+/// `out_group_pic_path`
+///
+/// The group picture is saved as lossless WebP or JPEG by the extension, and as PNG otherwise.
 pub fn generate_group_pic<I, O, S>(
     avatars_dir: I,
     out_group_pic_path: O,
     num_of_avatars_in_a_row: Option<u32>,
     header_text: S,
-) where
+) -> Result<(), GenPicError>
+where
     I: AsRef<Path>,
     O: AsRef<Path>,
     S: AsRef<str>,
 {
-    // configure the group pic
-    let header_h = 64;
-    let header_text = header_text.as_ref();
-    let header_font_size = 54.;
-    let mask_radius = 64;
-    let avatars_dir = avatars_dir.as_ref();
-
-    // calculate the rest of the configuration
-    let num_of_avatars = fs::read_dir(avatars_dir).unwrap().count() as u32;
-    let num_of_avatars_in_a_row =
-        num_of_avatars_in_a_row.unwrap_or_else(|| core::cmp::max(num_of_avatars.sqrt(), 5));
-    let num_of_rows = num_of_avatars.div_ceil(&num_of_avatars_in_a_row);
-    let group_pic_w = 128 * num_of_avatars_in_a_row;
-    let group_pic_h = header_h + 128 * num_of_rows;
-
-    // prepare the image buffer
-    let mut group_pic = ImageBuffer::from_pixel(group_pic_w, group_pic_h, DISCORD_COLOR);
-    // #[cfg(debug_assertions)]
-    // dbg!(group_pic.dimensions());
-
-    // render the header
-    render_header_glyph_brush(&mut group_pic, header_h, header_text, header_font_size);
-
-    // mask and tile the avatars
-    for (i, avatar_path) in fs::read_dir(avatars_dir).unwrap().enumerate() {
-        let avatar_path = avatar_path.unwrap().path();
-        let mut avatar_img = image::open(&avatar_path).unwrap().into_rgba8();
-        if avatar_img.dimensions() != (128, 128) {
-            avatar_img = resize(&avatar_img, 128, 128, image::imageops::FilterType::Lanczos3);
-        }
-        for (x, y, p) in avatar_img.enumerate_pixels_mut() {
-            if (x as i64 - 64) * (x as i64 - 64) + (y as i64 - 64) * (y as i64 - 64)
-                >= mask_radius * mask_radius
-            {
-                p.0.copy_from_slice(&DISCORD_COLOR.0);
-            }
-        }
-        let x_offset = i as u32 % num_of_avatars_in_a_row * 128;
-        let y_offset = i as u32 / num_of_avatars_in_a_row * 128 + header_h;
-        // println!(
-        //     "{:#?}: {:?} {:?}",
-        //     avatar_path,
-        //     avatar_img.dimensions(),
-        //     (x_offset, y_offset)
-        // );
+    // read_dir lists the files in no particular order
+    let mut avatar_paths = fs::read_dir(avatars_dir.as_ref())?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    avatar_paths.sort();
+    let avatars = avatar_paths
+        .par_iter()
+        .map(|avatar_path| Ok(image::open(avatar_path)?.into_rgba8()))
+        .collect::<Result<Vec<_>, GenPicError>>()?;
+    let config = GroupPicConfig {
+        header_text: header_text.as_ref().to_owned(),
+        num_of_avatars_in_a_row,
+        ..Default::default()
+    };
+    let group_pic = render_group_pic(&avatars, &config)?;
+    let extension = out_group_pic_path.as_ref().extension();
+    let format = match extension.and_then(|e| e.to_str()) {
+        Some("webp") => OutputFormat::WebPLossless,
+        Some("jpg") | Some("jpeg") => OutputFormat::Jpeg(DEFAULT_QUALITY),
+        _ => OutputFormat::Png,
+    };
+    fs::write(out_group_pic_path, encode_group_pic(&group_pic, format)?)?;
+    Ok(())
+}
+
+/// Render the group picture of the decoded `avatars` in memory
+pub fn render_group_pic(
+    avatars: &[RgbaImage],
+    config: &GroupPicConfig,
+) -> Result<RgbaImage, GenPicError> {
+    let (canvas, mut group_pic) = GroupPicCanvas::new(avatars.len(), config)?;
+    let tiles: Vec<_> = avatars.iter().enumerate().collect();
+    canvas.draw_tiles(&mut group_pic, &tiles)?;
+    canvas.draw_over_tiles(&mut group_pic)?;
+    Ok(group_pic)
+}
+
+/// Layout of a group picture and everything drawn around the tiles
+///
+/// This is what stays the same whatever the avatars look like, so that animated group pictures
+/// only composite the tiles for each frame.
+struct GroupPicCanvas<'a> {
+    config: &'a GroupPicConfig,
+    style: GroupPicStyle,
+    fonts: &'static Fonts,
+    /// Top left corner of each tile
+    positions: Vec<(u32, u32)>,
+    /// Height of a tile with its caption
+    tile_h: u32,
+    /// Width and height of the group picture
+    dimensions: (u32, u32),
+    captions: Vec<ShapedText>,
+    mask: GrayImage,
+    ring: Option<GrayImage>,
+    /// Footer with its top and height
+    footer: Option<(ShapedText, u32, u32)>,
+    /// Overlay images with their top left corners on the group picture
+    overlays: Vec<(Cow<'a, RgbaImage>, (i64, i64), f32)>,
+}
+
+impl<'a> GroupPicCanvas<'a> {
+    /// Lay out a group picture of `n` avatars, and draw everything under the tiles
+    fn new(n: usize, config: &'a GroupPicConfig) -> Result<(Self, RgbaImage), GenPicError> {
+        if n == 0 {
+            return Err(GenPicError::NoAvatars);
+        }
+        if let Some(captions) = &config.captions {
+            if captions.len() != n {
+                return Err(GenPicError::CaptionCountMismatch {
+                    avatars: n,
+                    captions: captions.len(),
+                });
+            }
+        }
+        if let Some(badges) = &config.badges {
+            if badges.len() != n {
+                return Err(GenPicError::BadgeCountMismatch {
+                    avatars: n,
+                    badges: badges.len(),
+                });
+            }
+        }
+
+        if let Some(rings) = &config.rings {
+            if rings.len() != n {
+                return Err(GenPicError::RingCountMismatch {
+                    avatars: n,
+                    rings: rings.len(),
+                });
+            }
+        }
+        if let Some(sections) = &config.sections {
+            let count = sections.iter().map(|s| s.count).sum();
+            if count != n {
+                return Err(GenPicError::SectionCountMismatch {
+                    avatars: n,
+                    sections: count,
+                });
+            }
+        }
+
+        let tile_size = config.tile_size;
+        if !TILE_SIZES.contains(&tile_size) {
+            return Err(GenPicError::InvalidTileSize(tile_size));
+        }
+
+        let mut style = config.style;
+        if config.transparent {
+            style.background_color = Rgba([0, 0, 0, 0]);
+            style.header_color = Rgba([0, 0, 0, 0]);
+        }
+
+        // fit each caption into the width of a tile
+        let fonts = Fonts::get()?;
+        let captions: Vec<ShapedText> = match &config.captions {
+            Some(captions) => {
+                let font_size = config.caption_font_size;
+                let max_w = tile_size - 2 * CAPTION_MARGIN;
+                captions
+                    .par_iter()
+                    .map(|c| {
+                        let caption = fonts.ellipsize(c.trim(), max_w as f32, font_size);
+                        fonts.shape(&caption, font_size)
+                    })
+                    .collect()
+            }
+            None => vec![],
+        };
+        let caption_h = captions
+            .iter()
+            .map(|c| c.height().ceil() as u32 + CAPTION_MARGIN)
+            .max()
+            .unwrap_or(0);
+
+        // place the tiles, then fit the header text into the space the layout leaves for it
+        let tile = TileMetrics {
+            size: tile_size,
+            h: tile_size + caption_h,
+            gap: style.tile_gap,
+            padding: style.padding,
+            round: config.mask_shape.fits_in_circle(),
+        };
+        let place = |n: usize| {
+            config.layout.place(
+                n as u32,
+                config.num_of_avatars_in_a_row,
+                &tile,
+                config.aspect_ratio,
+                config.header_h,
+            )
+        };
+        // discs to fill with the header color, as center and radius
+        let mut discs = vec![];
+        // sub-headers of the sections with their top left corners and colors
+        let mut titles = vec![];
+        let (header, header_top, header_bar_h, positions, group_pic_w, group_pic_h) = match &config
+            .sections
+        {
+            None => {
+                let mut placement = place(n)?;
+                match placement.header {
+                    HeaderPlacement::Top => {
+                        let header = layout_header(&config.header_text, placement.w, config, fonts);
+                        if config.layout == Layout::AspectRatioGrid {
+                            placement.pad_to_aspect_ratio(header.header_h, config.aspect_ratio);
+                        }
+                        // move the tiles below the header
+                        for (_, y) in &mut placement.positions {
+                            *y += header.header_h;
+                        }
+                        placement.h += header.header_h;
+                        let header_bar_h = header.header_h;
+                        (
+                            header,
+                            0,
+                            header_bar_h,
+                            placement.positions,
+                            placement.w,
+                            placement.h,
+                        )
+                    }
+                    HeaderPlacement::Center { radius } => {
+                        // keep the text inside the square inscribed in the disc
+                        let max_w = (radius as f32 * 2f32.sqrt()) as u32 + 2 * HEADER_MARGIN;
+                        let header = layout_header(&config.header_text, max_w, config, fonts);
+                        let top = (placement.h / 2).saturating_sub(header.header_h / 2);
+                        discs.push(((placement.w / 2, placement.h / 2), radius));
+                        (
+                            header,
+                            top,
+                            0,
+                            placement.positions,
+                            placement.w,
+                            placement.h,
+                        )
+                    }
+                }
+            }
+            Some(sections) => {
+                // every section is laid out on its own and stacked below the header
+                let sections: Vec<_> = sections.iter().filter(|s| s.count > 0).collect();
+                let placements = sections
+                    .iter()
+                    .map(|s| place(s.count))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let w = placements.iter().map(|p| p.w).max().unwrap_or(0);
+                let header = layout_header(&config.header_text, w, config, fonts);
+                let font_size = config.section_font_size;
+                let mut positions = Vec::with_capacity(n);
+                let mut y = header.header_h;
+                for (section, placement) in sections.iter().zip(placements) {
+                    let x = (w - placement.w) / 2;
+                    let color = section.color.unwrap_or(style.text_color);
+                    match placement.header {
+                        HeaderPlacement::Top => {
+                            let max_w = w.saturating_sub(2 * HEADER_MARGIN) as f32;
+                            let title = fonts.ellipsize(section.title.trim(), max_w, font_size);
+                            let title = fonts.shape(&title, font_size);
+                            let title_h = title.height().ceil() as u32;
+                            titles.push((title, (w / 2, y + HEADER_MARGIN), color));
+                            y += title_h + HEADER_MARGIN;
+                        }
+                        HeaderPlacement::Center { radius } => {
+                            let center = (x + placement.w / 2, y + placement.h / 2);
+                            let max_w = radius as f32 * 2f32.sqrt();
+                            let title = fonts.ellipsize(section.title.trim(), max_w, font_size);
+                            let title = fonts.shape(&title, font_size);
+                            let title_y = center.1.saturating_sub(title.height() as u32 / 2);
+                            titles.push((title, (center.0, title_y), color));
+                            discs.push((center, radius));
+                        }
+                    }
+                    positions.extend(placement.positions.iter().map(|&(px, py)| (x + px, y + py)));
+                    y += placement.h;
+                }
+                let header_bar_h = header.header_h;
+                (header, 0, header_bar_h, positions, w, y)
+            }
+        };
+
+        // the footer runs under everything else
+        let footer = config
+            .footer
+            .as_deref()
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .map(|f| {
+                let max_w = group_pic_w.saturating_sub(2 * HEADER_MARGIN) as f32;
+                let footer = fonts.ellipsize(f, max_w, config.footer_font_size);
+                fonts.shape(&footer, config.footer_font_size)
+            });
+        let footer_top = group_pic_h;
+        let footer_h = footer
+            .as_ref()
+            .map_or(0, |f| f.height().ceil() as u32 + 2 * CAPTION_MARGIN);
+        let group_pic_h = group_pic_h + footer_h;
+
+        // prepare the image buffer
+        let mut group_pic = match &config.background {
+            Some(background) => {
+                render_background(background, group_pic_w, group_pic_h, style.background_color)
+            }
+            None => ImageBuffer::from_pixel(group_pic_w, group_pic_h, style.background_color),
+        };
         group_pic
-            .copy_from(&avatar_img, x_offset, y_offset)
-            .unwrap();
+            .rows_mut()
+            .enumerate()
+            .filter(|&(y, _)| y < header_bar_h as usize || y >= footer_top as usize)
+            .flat_map(|(_, row)| row)
+            .for_each(|p| *p = style.header_color);
+        for ((cx, cy), radius) in discs {
+            let disc = render_mask(MaskShape::Circle, 2 * radius);
+            fill_coverage(
+                &mut group_pic,
+                &disc,
+                cx as i64 - radius as i64,
+                cy as i64 - radius as i64,
+                style.header_color,
+            );
+        }
+
+        // render the header and the sub-headers of the sections
+        draw_header(
+            &mut group_pic,
+            &header,
+            config.header_icon.as_ref(),
+            header_top,
+            fonts,
+            &style,
+        )?;
+        // sub-headers are centered on their anchors
+        let clip = Clip::image(&group_pic);
+        for (title, (x, y), color) in &titles {
+            let anchor = (*x as f32, *y as f32);
+            let text_style = style.text_style(title.font_size, *color);
+            fonts.draw_line(
+                &mut group_pic,
+                title,
+                anchor,
+                Align::Center,
+                clip,
+                &text_style,
+            )?;
+        }
+
+        let mask = render_mask(config.mask_shape, tile_size);
+        let ring = config.rings.as_ref().map(|_| {
+            let thickness = config.ring_thickness;
+            render_ring(
+                config.mask_shape,
+                tile_size,
+                thickness,
+                config.ring_antialiasing,
+            )
+        });
+        let overlays = config
+            .overlays
+            .iter()
+            .map(|overlay| place_overlay(overlay, group_pic_w, group_pic_h))
+            .collect();
+        let canvas = Self {
+            config,
+            style,
+            fonts,
+            positions,
+            tile_h: tile.h,
+            dimensions: group_pic.dimensions(),
+            captions,
+            mask,
+            ring,
+            footer: footer.map(|footer| (footer, footer_top, footer_h)),
+            overlays,
+        };
+        Ok((canvas, group_pic))
+    }
+
+    /// Mask and draw `tiles` of avatars with their indices, along with their rings, badges and
+    /// captions
+    fn draw_tiles(
+        &self,
+        group_pic: &mut RgbaImage,
+        tiles: &[(usize, &RgbaImage)],
+    ) -> Result<(), GenPicError> {
+        let (config, style, fonts) = (self.config, &self.style, self.fonts);
+        let tile_size = config.tile_size;
+        // resizing and masking each avatar is independent of the others, but the tiles are
+        // blended in order, so that the output is the same however the work is split. Tiles are
+        // prepared a few per thread at a time to bound the memory they take.
+        let chunk_len = 4 * rayon::current_num_threads();
+        for chunk in tiles.chunks(chunk_len) {
+            let masked: Vec<_> = chunk
+                .par_iter()
+                .map(|(_, avatar_img)| mask_image(avatar_img, &self.mask))
+                .collect();
+            for (&(i, _), tile) in chunk.iter().zip(&masked) {
+                let (x_offset, y_offset) = self.positions[i];
+                blend_image(group_pic, tile, (x_offset, y_offset));
+                // outline the avatar over its edge
+                if let (Some(ring), Some(color)) = (&self.ring, config.rings.as_ref().map(|r| r[i]))
+                {
+                    fill_coverage(group_pic, ring, x_offset as i64, y_offset as i64, color);
+                }
+
+                if let Some(badges) = config.badges.as_ref().and_then(|b| b.get(i)) {
+                    draw_badges(
+                        group_pic,
+                        badges,
+                        (x_offset + tile_size, y_offset + tile_size),
+                        tile_size,
+                        style,
+                        fonts,
+                    )?;
+                }
+
+                // center the caption under the avatar
+                if let Some(caption) = self.captions.get(i) {
+                    let anchor = (
+                        (x_offset + tile_size / 2) as f32,
+                        (y_offset + tile_size) as f32,
+                    );
+                    let text_style = style.text_style(caption.font_size, style.text_color);
+                    fonts.draw_line(
+                        group_pic,
+                        caption,
+                        anchor,
+                        Align::Center,
+                        self.cell(i),
+                        &text_style,
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Draw the footer and the overlays, which go over the tiles
+    fn draw_over_tiles(&self, group_pic: &mut RgbaImage) -> Result<(), GenPicError> {
+        if let Some((footer, footer_top, footer_h)) = &self.footer {
+            let anchor = (
+                group_pic.width() as f32 / 2.,
+                (footer_top + CAPTION_MARGIN) as f32,
+            );
+            let text_style = self
+                .style
+                .text_style(footer.font_size, self.style.text_color);
+            let clip = Clip::rows(group_pic, *footer_top, *footer_h);
+            self.fonts
+                .draw_line(group_pic, footer, anchor, Align::Center, clip, &text_style)?;
+        }
+        for (img, at, opacity) in &self.overlays {
+            blend_overlay(group_pic, img, *at, *opacity);
+        }
+        Ok(())
+    }
+
+    /// Area tile `i` draws in, which is the tile with its caption and half of the gap around it
+    fn cell(&self, i: usize) -> Clip {
+        let (x, y) = self.positions[i];
+        let (w, h) = self.dimensions;
+        let half_gap = self.style.tile_gap / 2;
+        let (left, top) = (x.saturating_sub(half_gap), y.saturating_sub(half_gap));
+        let right = (x + self.config.tile_size + half_gap).min(w);
+        let bottom = (y + self.tile_h + half_gap).min(h);
+        Clip {
+            x: left,
+            y: top,
+            w: right.saturating_sub(left),
+            h: bottom.saturating_sub(top),
+        }
+    }
+
+    /// Extend `redrawn` with every tile whose cell overlaps the cell of a redrawn tile
+    ///
+    /// Tiles are blended in order, so the tiles overlapping a redrawn tile are redrawn too for
+    /// the overlap to come out the same.
+    fn spread_redrawn(&self, redrawn: &mut [bool]) {
+        let overlaps = |i: usize, j: usize| {
+            let (a, b) = (self.cell(i), self.cell(j));
+            a.x < b.x + b.w && b.x < a.x + a.w && a.y < b.y + b.h && b.y < a.y + a.h
+        };
+        let mut spreading = true;
+        while spreading {
+            spreading = false;
+            for j in 0..redrawn.len() {
+                if !redrawn[j] && (0..redrawn.len()).any(|i| redrawn[i] && overlaps(i, j)) {
+                    redrawn[j] = true;
+                    spreading = true;
+                }
+            }
+        }
+    }
+}
+
+/// Render only the header of a group picture `w` pixels wide, as it appears above the tiles
+pub fn render_header(config: &GroupPicConfig, w: u32) -> Result<RgbaImage, GenPicError> {
+    let fonts = Fonts::get()?;
+    let header = layout_header(&config.header_text, w, config, fonts);
+    let header_color = if config.transparent {
+        Rgba([0, 0, 0, 0])
+    } else {
+        config.style.header_color
+    };
+    let mut header_pic = ImageBuffer::from_pixel(w, header.header_h, header_color);
+    draw_header(
+        &mut header_pic,
+        &header,
+        config.header_icon.as_ref(),
+        0,
+        fonts,
+        &config.style,
+    )?;
+    Ok(header_pic)
+}
+
+/// Resize `img` with Lanczos3 in premultiplied alpha
+///
+/// Resizing straight alpha would bleed the color of transparent pixels, usually black, into the
+/// edges of opaque areas.
+fn resize_premultiplied(img: &RgbaImage, w: u32, h: u32) -> RgbaImage {
+    if img.pixels().all(|p| p.0[3] == 255) {
+        return resize(img, w, h, image::imageops::FilterType::Lanczos3);
+    }
+    let premultiplied: ImageBuffer<Rgba<f32>, Vec<f32>> =
+        ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+            let [r, g, b, a] = img.get_pixel(x, y).0.map(|c| c as f32 / 255.);
+            Rgba([r * a, g * a, b * a, a])
+        });
+    let resized = resize(&premultiplied, w, h, image::imageops::FilterType::Lanczos3);
+    ImageBuffer::from_fn(w, h, |x, y| {
+        let [r, g, b, a] = resized.get_pixel(x, y).0;
+        if a <= 0. {
+            return Rgba([0, 0, 0, 0]);
+        }
+        let unpremultiply = |c: f32| ((c / a).clamp(0., 1.) * 255.).round() as u8;
+        Rgba([
+            unpremultiply(r),
+            unpremultiply(g),
+            unpremultiply(b),
+            (a.clamp(0., 1.) * 255.).round() as u8,
+        ])
+    })
+}
+
+/// Scale `background` to cover `w` by `h`, then blur it and fade it towards `color`
+fn render_background(background: &Background, w: u32, h: u32, color: Rgba<u8>) -> RgbaImage {
+    let mut group_pic = ImageBuffer::from_pixel(w, h, color);
+    let (img_w, img_h) = background.image.dimensions();
+    if img_w == 0 || img_h == 0 {
+        return group_pic;
+    }
+    let scale = (w as f32 / img_w as f32).max(h as f32 / img_h as f32);
+    let scaled_w = ((img_w as f32 * scale).round() as u32).max(w);
+    let scaled_h = ((img_h as f32 * scale).round() as u32).max(h);
+    let scaled = resize_premultiplied(&background.image, scaled_w, scaled_h);
+    let mut cover = crop_imm(&scaled, (scaled_w - w) / 2, (scaled_h - h) / 2, w, h).to_image();
+    if background.blur > 0. {
+        cover = blur(&cover, background.blur);
+    }
+
+    let dim = background.dim.clamp(0., 1.);
+    for (x, y, p) in cover.enumerate_pixels() {
+        let faded = p.map2(&color, |a, b| {
+            (a as f32 * (1. - dim) + b as f32 * dim).round() as u8
+        });
+        blend_pixel(&mut group_pic, x as i64, y as i64, h, &faded);
+    }
+    group_pic
+}
+
+/// Resize `img` to the size of `mask`, cut it into the mask and blend it onto `image` with its
+/// top left corner at `(x, y)`
+fn draw_masked(image: &mut RgbaImage, img: &RgbaImage, mask: &GrayImage, (x, y): (u32, u32)) {
+    blend_image(image, &mask_image(img, mask), (x, y));
+}
+
+/// Resize `img` to the size of `mask` and cut it into the mask
+fn mask_image(img: &RgbaImage, mask: &GrayImage) -> RgbaImage {
+    let mut img = if img.dimensions() != mask.dimensions() {
+        resize_premultiplied(img, mask.width(), mask.height())
+    } else {
+        img.clone()
+    };
+    for (p, m) in img.pixels_mut().zip(mask.pixels()) {
+        p.0[3] = (p.0[3] as u32 * m.0[0] as u32 / 255) as u8;
+    }
+    img
+}
+
+/// Blend `img` onto `image` with its top left corner at `(x, y)`
+fn blend_image(image: &mut RgbaImage, img: &RgbaImage, (x, y): (u32, u32)) {
+    let clip_h = image.height();
+    for (dx, dy, p) in img.enumerate_pixels() {
+        blend_pixel(image, (x + dx) as i64, (y + dy) as i64, clip_h, p);
+    }
+}
+
+/// Image of `overlay` as drawn on a `w` by `h` group picture, with its top left corner and
+/// opacity
+fn place_overlay(overlay: &Overlay, w: u32, h: u32) -> (Cow<'_, RgbaImage>, (i64, i64), f32) {
+    let img = if overlay.anchor == OverlayAnchor::Fill {
+        Cow::Owned(resize_premultiplied(&overlay.image, w, h))
+    } else {
+        Cow::Borrowed(&overlay.image)
+    };
+    let (ow, oh) = overlay.image.dimensions();
+    let m = overlay.margin as i64;
+    let left = m;
+    let right = w as i64 - ow as i64 - m;
+    let top = m;
+    let bottom = h as i64 - oh as i64 - m;
+    let at = match overlay.anchor {
+        OverlayAnchor::TopLeft => (left, top),
+        OverlayAnchor::TopRight => (right, top),
+        OverlayAnchor::BottomLeft => (left, bottom),
+        OverlayAnchor::BottomRight => (right, bottom),
+        OverlayAnchor::Center => ((w as i64 - ow as i64) / 2, (h as i64 - oh as i64) / 2),
+        OverlayAnchor::Fill => (0, 0),
+    };
+    (img, at, overlay.opacity.clamp(0., 1.))
+}
+
+/// Blend an overlay image onto the group picture at `(x, y)`, clipped to the group picture
+fn blend_overlay(image: &mut RgbaImage, img: &RgbaImage, (x, y): (i64, i64), opacity: f32) {
+    let h = image.height();
+    for (dx, dy, p) in img.enumerate_pixels() {
+        let mut p = *p;
+        p.0[3] = (p.0[3] as f32 * opacity).round() as u8;
+        blend_pixel(image, x + dx as i64, y + dy as i64, h, &p);
+    }
+}
+
+/// Render the coverage of `shape` in a `size` by `size` square
+fn render_mask(shape: MaskShape, size: u32) -> GrayImage {
+    render_coverage(size, size, COVERAGE_SAMPLES, |u, v| shape.contains(u, v))
+}
+
+/// Render the coverage of a ring `thickness` wide along the edge of `shape` in a `size` by `size`
+/// square, with `samples` by `samples` samples per pixel
+fn render_ring(shape: MaskShape, size: u32, thickness: u32, samples: u32) -> GrayImage {
+    let inner = size.saturating_sub(2 * thickness);
+    if inner == 0 {
+        return render_coverage(size, size, samples, |u, v| shape.contains(u, v));
+    }
+    // the inside of the ring is the shape scaled down to the inner size
+    let scale = size as f32 / inner as f32;
+    render_coverage(size, size, samples, |u, v| {
+        shape.contains(u, v) && !shape.contains(u * scale, v * scale)
+    })
+}
+
+/// Render the coverage of a shape in a `w` by `h` rectangle
+///
+/// `contains` tells whether the point `(u, v)` is inside the shape, where `v` goes from -1 to 1
+/// downwards and `u` from `-w / h` to `w / h`. Each pixel is sampled on a 4 by 4 grid, so that
+/// the edges of the shape are anti-aliased.
+fn render_coverage(w: u32, h: u32, samples: u32, contains: impl Fn(f32, f32) -> bool) -> GrayImage {
+    let samples = samples.max(1);
+    let half = h as f32 / 2.;
+    ImageBuffer::from_fn(w, h, |x, y| {
+        let mut covered = 0;
+        for i in 0..samples {
+            for j in 0..samples {
+                let u = (x as f32 + (i as f32 + 0.5) / samples as f32 - w as f32 / 2.) / half;
+                let v = (y as f32 + (j as f32 + 0.5) / samples as f32) / half - 1.;
+                if contains(u, v) {
+                    covered += 1;
+                }
+            }
+        }
+        Luma([(covered * 255 / (samples * samples)) as u8])
+    })
+}
+
+/// Blend `color` onto `image` at `(x, y)`, weighted by `coverage`
+fn fill_coverage(image: &mut RgbaImage, coverage: &GrayImage, x: i64, y: i64, color: Rgba<u8>) {
+    let clip_h = image.height();
+    for (cx, cy, c) in coverage.enumerate_pixels() {
+        let mut color = color;
+        color.0[3] = (color.0[3] as u32 * c.0[0] as u32 / 255) as u8;
+        blend_pixel(image, x + cx as i64, y + cy as i64, clip_h, &color);
+    }
+}
+
+/// Draw the voice state badges from right to left, ending at the bottom right corner of the tile
+/// `(right, bottom)`
+fn draw_badges(
+    group_pic: &mut RgbaImage,
+    badges: &VoiceBadges,
+    (right, bottom): (u32, u32),
+    tile_size: u32,
+    style: &GroupPicStyle,
+    fonts: &Fonts,
+) -> Result<(), GenPicError> {
+    // circles, or a pill for wider badges
+    let pill = |w: u32, h: u32| {
+        let straight = w as f32 / h as f32 - 1.;
+        move |u: f32, v: f32| distance_to_segment(u, v, (-straight, 0.), (straight, 0.)) <= 1.
+    };
+    let size = BADGE_SIZE * tile_size / 128;
+    let border = (BADGE_BORDER * tile_size / 128).max(1);
+    let y = (bottom - border - size) as i64;
+    let mut x = (right - border) as i64;
+    for badge in badges.badges() {
+        let w = badge.width(size);
+        x -= w as i64;
+
+        // cut the badge out of the avatar with a ring in the background color
+        let (ring_w, ring_h) = (w + 2 * border, size + 2 * border);
+        let ring = render_coverage(ring_w, ring_h, COVERAGE_SAMPLES, pill(ring_w, ring_h));
+        fill_coverage(
+            group_pic,
+            &ring,
+            x - border as i64,
+            y - border as i64,
+            style.background_color,
+        );
+        let body = render_coverage(w, size, COVERAGE_SAMPLES, pill(w, size));
+        fill_coverage(group_pic, &body, x, y, badge.color());
+
+        let white = Rgba([255, 255, 255, 255]);
+        if badge == Badge::Streaming {
+            let live = fonts.shape("LIVE", size as f32 * 0.6);
+            let anchor = (
+                x as f32 + w as f32 / 2.,
+                y as f32 + (size as f32 - live.height()).max(0.) / 2.,
+            );
+            let clip = Clip::image(group_pic);
+            let text_style = TextStyle::new(live.font_size, white);
+            fonts.draw_line(group_pic, &live, anchor, Align::Center, clip, &text_style)?;
+        } else {
+            let icon = render_coverage(w, size, COVERAGE_SAMPLES, |u, v| {
+                badge.icon_contains(u / 0.65, v / 0.65)
+            });
+            fill_coverage(group_pic, &icon, x, y, white);
+        }
+        x -= border as i64;
+    }
+    Ok(())
+}
+
+/// Encode the group picture as PNG bytes
+pub fn encode_group_pic_png(group_pic: &RgbaImage) -> Result<Vec<u8>, GenPicError> {
+    let mut bytes = Vec::new();
+    group_pic.write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)?;
+    Ok(bytes)
+}
+
+/// Encode the group picture in `format`
+pub fn encode_group_pic(
+    group_pic: &RgbaImage,
+    format: OutputFormat,
+) -> Result<Vec<u8>, GenPicError> {
+    let (w, h) = group_pic.dimensions();
+    match format {
+        OutputFormat::Png => encode_group_pic_png(group_pic),
+        OutputFormat::WebPLossless => Ok(webp::Encoder::from_rgba(group_pic.as_raw(), w, h)
+            .encode_lossless()
+            .to_vec()),
+        OutputFormat::WebP(quality) => Ok(webp::Encoder::from_rgba(group_pic.as_raw(), w, h)
+            .encode(quality as f32)
+            .to_vec()),
+        OutputFormat::Jpeg(quality) => {
+            let mut bytes = Vec::new();
+            group_pic.write_to(
+                &mut Cursor::new(&mut bytes),
+                ImageOutputFormat::Jpeg(quality),
+            )?;
+            Ok(bytes)
+        }
+    }
+}
+
+/// Encode the group picture in no more than `max_bytes`
+///
+/// Lossless encodings are tried first, then lossy WebP with decreasing quality, and at last the
+/// group picture is downsampled until it fits.
+pub fn encode_group_pic_within_budget(
+    group_pic: &RgbaImage,
+    max_bytes: usize,
+) -> Result<(OutputFormat, Vec<u8>), GenPicError> {
+    let formats = [OutputFormat::Png, OutputFormat::WebPLossless]
+        .iter()
+        .copied()
+        .chain(
+            [90, 80, 70, 60, 50]
+                .iter()
+                .map(|&quality| OutputFormat::WebP(quality)),
+        );
+    let mut smallest = usize::MAX;
+    for format in formats {
+        let bytes = encode_group_pic(group_pic, format)?;
+        if bytes.len() <= max_bytes {
+            return Ok((format, bytes));
+        }
+        smallest = smallest.min(bytes.len());
+    }
+
+    // shrink by a quarter each time, down to about the size of a single avatar
+    let format = OutputFormat::WebP(75);
+    let (mut w, mut h) = group_pic.dimensions();
+    while w.min(h) * 3 / 4 >= 64 {
+        w = w * 3 / 4;
+        h = h * 3 / 4;
+        let bytes = encode_group_pic(&resize_premultiplied(group_pic, w, h), format)?;
+        if bytes.len() <= max_bytes {
+            return Ok((format, bytes));
+        }
+        smallest = smallest.min(bytes.len());
+    }
+    Err(GenPicError::OverBudget {
+        bytes: smallest,
+        max_bytes,
+    })
+}
+
+/// Encode the frames of the group picture as a looping GIF
+pub fn encode_group_pic_gif(frames: Vec<Frame>) -> Result<Vec<u8>, GenPicError> {
+    let mut bytes = Vec::new();
+    {
+        // the trailer is written when the encoder is dropped
+        let mut encoder = GifEncoder::new_with_speed(&mut bytes, 10);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames)?;
     }
+    Ok(bytes)
+}
 
-    group_pic.save(out_group_pic_path.as_ref()).unwrap();
+/// Decode every frame of an avatar, which is a single frame unless the avatar is an animated GIF
+pub fn decode_avatar_frames(bytes: &[u8]) -> Result<Vec<Frame>, GenPicError> {
+    if image::guess_format(bytes)? == ImageFormat::Gif {
+        let frames = GifDecoder::new(Cursor::new(bytes))?
+            .into_frames()
+            .collect_frames()?;
+        if !frames.is_empty() {
+            return Ok(frames);
+        }
+    }
+    Ok(vec![Frame::new(
+        image::load_from_memory(bytes)?.into_rgba8(),
+    )])
 }
 
-fn render_header_glyph_brush(
-    group_pic: &mut RgbaImage,
+/// Delay of a frame in milliseconds, where delays of 10 ms or less are 100 ms like in browsers
+fn frame_delay_ms(frame: &Frame) -> u32 {
+    let (numer, denom) = frame.delay().numer_denom_ms();
+    match numer / denom.max(1) {
+        ms if ms <= 10 => 100,
+        ms => ms,
+    }
+}
+
+/// Frames of an animated avatar looping every `duration` milliseconds
+struct AvatarTimeline<'a> {
+    frames: &'a [Frame],
+    /// Time each frame ends at within a loop
+    ends: Vec<u32>,
+    duration: u32,
+}
+
+impl<'a> AvatarTimeline<'a> {
+    fn new(frames: &'a [Frame]) -> Self {
+        let ends: Vec<u32> = frames
+            .iter()
+            .scan(0, |t, frame| {
+                *t += frame_delay_ms(frame);
+                Some(*t)
+            })
+            .collect();
+        let duration = ends.last().copied().unwrap_or(0);
+        Self {
+            frames,
+            ends,
+            duration,
+        }
+    }
+
+    fn frame_at(&self, t: u32) -> &'a RgbaImage {
+        let t = t % self.duration;
+        let i = self.ends.partition_point(|&end| end <= t);
+        self.frames[i.min(self.frames.len() - 1)].buffer()
+    }
+}
+
+/// Render an animated group picture from the frames of each avatar, encoded as GIF
+///
+/// Animated avatars loop on their own timelines side by side, while static avatars stay put.
+/// Frames are merged until the GIF fits `budget`.
+pub fn render_animated_group_pic(
+    avatars: &[Vec<Frame>],
+    config: &GroupPicConfig,
+    budget: &AnimationBudget,
+) -> Result<Vec<u8>, GenPicError> {
+    if let Some(avatar) = avatars.iter().position(|frames| frames.is_empty()) {
+        return Err(GenPicError::NoFrames { avatar });
+    }
+    let timelines: Vec<_> = avatars
+        .iter()
+        .map(|frames| AvatarTimeline::new(frames))
+        .collect();
+    let duration = timelines
+        .iter()
+        .filter(|tl| tl.frames.len() > 1)
+        .map(|tl| tl.duration)
+        .max()
+        .unwrap_or(0)
+        .min(budget.max_duration_ms);
+
+    // every time any of the animated avatars changes its frame
+    let mut changes = vec![0];
+    for tl in timelines.iter().filter(|tl| tl.frames.len() > 1) {
+        for loop_start in (0..duration).step_by(tl.duration as usize) {
+            changes.extend(
+                tl.ends
+                    .iter()
+                    .map(|end| loop_start + end)
+                    .filter(|&t| t < duration),
+            );
+        }
+    }
+
+    changes.sort_unstable();
+    changes.dedup();
+
+    // the background, the header and the tiles which never change are drawn only once
+    let (canvas, mut still) = GroupPicCanvas::new(avatars.len(), config)?;
+    let mut redrawn: Vec<bool> = timelines.iter().map(|tl| tl.frames.len() > 1).collect();
+    canvas.spread_redrawn(&mut redrawn);
+    let still_tiles: Vec<_> = timelines
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| !redrawn[i])
+        .map(|(i, tl)| (i, tl.frame_at(0)))
+        .collect();
+    canvas.draw_tiles(&mut still, &still_tiles)?;
+
+    let mut max_frames = budget.max_frames.max(1);
+    loop {
+        // drop the changes too close to the previous frame for the number of frames
+        let step = Integer::div_ceil(&(duration / max_frames).max(MIN_FRAME_DELAY_MS), &10) * 10;
+        let mut starts: Vec<u32> = Vec::with_capacity(changes.len());
+        for &t in &changes {
+            if starts.last().map_or(true, |&start| t >= start + step) {
+                starts.push(t);
+            }
+        }
+
+        let mut frames = Vec::with_capacity(starts.len());
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(duration);
+            let mut group_pic = still.clone();
+            let tiles: Vec<_> = timelines
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| redrawn[i])
+                .map(|(i, tl)| (i, tl.frame_at(start)))
+                .collect();
+            canvas.draw_tiles(&mut group_pic, &tiles)?;
+            canvas.draw_over_tiles(&mut group_pic)?;
+            let delay = Delay::from_numer_denom_ms(end.saturating_sub(start), 1);
+            frames.push(Frame::from_parts(group_pic, 0, 0, delay));
+        }
+        let bytes = encode_group_pic_gif(frames)?;
+        if bytes.len() <= budget.max_bytes {
+            return Ok(bytes);
+        }
+        if starts.len() <= 1 {
+            return Err(GenPicError::OverBudget {
+                bytes: bytes.len(),
+                max_bytes: budget.max_bytes,
+            });
+        }
+        max_frames = (starts.len() as u32 / 2).max(1);
+    }
+}
+
+/// Lines of the header text fitted into the width of the group picture
+struct HeaderLayout {
+    /// Shaped lines from top to bottom
+    lines: Vec<ShapedText>,
+    /// Height of each line in pixels
+    line_h: u32,
+    /// Shaped subtitle under the lines
+    subtitle: Option<ShapedText>,
+    /// Width and height of the icon left of the text in pixels, or 0 if there is no icon
+    icon_size: u32,
+    /// Height of the whole header in pixels
     header_h: u32,
+}
+
+/// Fit `header_text` into `group_pic_w`
+///
+/// The text is first shrunk down to `config.header_min_font_size`. If it still does not fit, it
+/// wraps onto up to `config.header_max_lines` lines, growing the header by a line height for each
+/// extra line, and the last line is ellipsized. The icon narrows the space for the text, and the
+/// subtitle grows the header by its own height.
+fn layout_header(
     header_text: &str,
-    header_font_size: f32,
-) {
-    let group_pic_w = group_pic.width();
-    let noto = FontRef::try_from_slice(FONT_DATA).expect("error loading font");
-    // let noto_emoji = FontRef::try_from_slice(EMOJI_FONT_DATA).expect("error loading emoji font");
-    let fonts = &[noto.clone()];
-    // let sections: Vec<SectionText> = vec![];
-    // for (i, grapheme) in unic::segment::GraphemeIndices::new(header_text) {
-
-    // }
-    let glyphs = Layout::default().calculate_glyphs(
-        fonts,
-        &SectionGeometry {
-            screen_position: (0., 0.),
-            bounds: (group_pic_w as f32, header_h as f32),
-        },
-        &[SectionText {
-            text: header_text,
-            scale: PxScale::from(header_font_size as f32),
-            font_id: FontId(0),
-        }],
-    );
-    let scaled = noto.as_scaled(header_font_size as f32);
-    let layout_h = (scaled.ascent() - scaled.descent()).ceil() as u32;
-    let layout_w = {
-        let min_x = noto
-            .outline_glyph(glyphs.first().unwrap().glyph.clone())
-            .unwrap()
-            .px_bounds()
-            .min
-            .x;
-        let max_x = noto
-            .outline_glyph(glyphs.last().unwrap().glyph.clone())
-            .unwrap()
-            .px_bounds()
-            .min
-            .x;
-        (max_x - min_x) as u32
+    group_pic_w: u32,
+    config: &GroupPicConfig,
+    fonts: &Fonts,
+) -> HeaderLayout {
+    let subtitle = config
+        .subtitle
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty());
+    let subtitle_size = config.subtitle_font_size;
+    let subtitle_h = subtitle.map_or(0, |s| fonts.shape(s, subtitle_size).height().ceil() as u32);
+
+    // the icon spans the title and the subtitle, and sits beside them
+    let icon_size = match config.header_icon {
+        Some(_) => (config.header_h + subtitle_h).saturating_sub(2 * HEADER_MARGIN),
+        None => 0,
     };
-    let x_offset = (group_pic_w - layout_w) / 2;
-    let y_offset = (header_h - layout_h) / 2;
-    for SectionGlyph { glyph, .. } in glyphs {
-        let font = &noto;
-        // if let Some(gi) = font.glyph_raster_image(glyph.id, header_font_size) {
-        //     dbg!(gi.format);
-        //     let d = image::io::Reader::new(Cursor::new(gi.data))
-        //         .with_guessed_format()
-        //         .unwrap()
-        //         .decode()
-        //         .unwrap();
-        //     group_pic
-        //         .copy_from(&d, glyph.position.x as u32, glyph.position.y as u32)
-        //         .unwrap();
-        //     continue;
-        // }
-        if let Some(q) = font.outline_glyph(glyph) {
-            let b = q.px_bounds();
-            q.draw(|x, y, c| {
-                group_pic
-                    .get_pixel_mut(x_offset + x + b.min.x as u32, y_offset + y + b.min.y as u32)
-                    .blend(&image::Rgba([240, 240, 240, (c * 255.) as u8]))
-            });
+    let icon_w = if icon_size > 0 {
+        icon_size + HEADER_MARGIN
+    } else {
+        0
+    };
+    let max_w = group_pic_w.saturating_sub(2 * HEADER_MARGIN + icon_w) as f32;
+    let subtitle = subtitle.map(|s| {
+        let subtitle = fonts.ellipsize(s, max_w, subtitle_size);
+        fonts.shape(&subtitle, subtitle_size)
+    });
+
+    let header_text = header_text.trim();
+    let lines = if header_text.is_empty() {
+        vec![]
+    } else {
+        // the width of shaped text is proportional to its font size
+        let mut font_size = config.header_font_size;
+        let mut line = fonts.shape(header_text, font_size);
+        if line.width > max_w {
+            font_size = (font_size * max_w / line.width)
+                .floor()
+                .max(config.header_min_font_size);
+            line = fonts.shape(header_text, font_size);
+        }
+        if line.width <= max_w {
+            vec![line]
+        } else {
+            let max_lines = config.header_max_lines.max(1) as usize;
+            fonts
+                .wrap(header_text, max_w, max_lines, font_size)
+                .iter()
+                .map(|l| fonts.shape(l, font_size))
+                .collect()
         }
+    };
+
+    let line_h = lines
+        .iter()
+        .map(ShapedText::height)
+        .fold(0f32, f32::max)
+        .ceil() as u32;
+    let extra_lines = (lines.len() as u32).saturating_sub(1);
+    let header_h = config.header_h.max(line_h) + extra_lines * line_h + subtitle_h;
+    HeaderLayout {
+        lines,
+        line_h,
+        subtitle,
+        icon_size,
+        header_h,
+    }
+}
+
+/// Blend `color` onto the pixel at `(x, y)`, unless it is outside of the image or below `clip_h`
+fn blend_pixel(image: &mut RgbaImage, x: i64, y: i64, clip_h: u32, color: &Rgba<u8>) {
+    if x < 0 || y < 0 || x >= image.width() as i64 || y >= clip_h.min(image.height()) as i64 {
+        return;
+    }
+    blend_over(image.get_pixel_mut(x as u32, y as u32), color);
+}
+
+/// Composite `src` over `dst` in straight alpha, rounded to the nearest value
+///
+/// Unlike `Pixel::blend` of `image`, an opaque `dst` stays fully opaque, so anti-aliased edges
+/// drawn onto an opaque picture leave no translucent pixels behind.
+fn blend_over(dst: &mut Rgba<u8>, src: &Rgba<u8>) {
+    let (sa, da) = (src.0[3] as u32, dst.0[3] as u32);
+    if sa == 0 {
+        return;
+    }
+    if sa == 255 {
+        *dst = *src;
+        return;
+    }
+    // sa + da·(1 − sa), and the colors weighted by their share of it, all scaled by 255
+    let a = sa * 255 + da * (255 - sa);
+    for c in 0..3 {
+        let premultiplied = src.0[c] as u32 * sa * 255 + dst.0[c] as u32 * da * (255 - sa);
+        dst.0[c] = ((premultiplied + a / 2) / a) as u8;
+    }
+    dst.0[3] = ((a + 127) / 255) as u8;
+}
+
+/// Draw the icon and the lines of the header, centered together in the header `top` pixels from
+/// the top of `group_pic`, over the header bar of `style` if it has one
+fn draw_header(
+    group_pic: &mut RgbaImage,
+    header: &HeaderLayout,
+    icon: Option<&RgbaImage>,
+    top: u32,
+    fonts: &Fonts,
+    style: &GroupPicStyle,
+) -> Result<(), GenPicError> {
+    let group_pic_w = group_pic.width();
+    let header_h = header.header_h;
+    let clip = Clip::rows(group_pic, top, header_h);
+    let subtitle_h = header
+        .subtitle
+        .as_ref()
+        .map_or(0., ShapedText::height)
+        .ceil() as u32;
+    let text_h = header.line_h * header.lines.len() as u32 + subtitle_h;
+    let text_w = header
+        .lines
+        .iter()
+        .chain(&header.subtitle)
+        .map(|line| line.width)
+        .fold(0f32, f32::max);
+
+    // center the icon and the text beside it together
+    let icon = icon.filter(|_| header.icon_size > 0);
+    let icon_w = icon.map_or(0, |_| header.icon_size + HEADER_MARGIN);
+    let left = (group_pic_w as f32 - icon_w as f32 - text_w).max(0.);
+    if let Some(bar_color) = style.header_bar.filter(|_| text_w > 0.) {
+        // the bar runs behind the icon and the text with a margin around them
+        let bar_w = icon_w + text_w.ceil() as u32 + 2 * HEADER_MARGIN;
+        let bar_h = (text_h.max(header.icon_size) + HEADER_MARGIN).min(header_h);
+        let bar = GrayImage::from_pixel(bar_w, bar_h, Luma([255]));
+        let x = (left / 2.) as i64 - HEADER_MARGIN as i64;
+        let y = (top + (header_h - bar_h) / 2) as i64;
+        fill_coverage(group_pic, &bar, x, y, bar_color);
+    }
+    if let Some(icon) = icon {
+        let mask = render_mask(MaskShape::Circle, header.icon_size);
+        let y_offset = top + header_h.saturating_sub(header.icon_size) / 2;
+        draw_masked(group_pic, icon, &mask, (left as u32 / 2, y_offset));
+    }
+    // every line is centered over the text
+    let center_x = (left + (2 * icon_w) as f32 + text_w) / 2.;
+
+    for (i, line) in header.lines.iter().enumerate() {
+        // center all lines together vertically
+        let y_offset = top
+            + header_h.saturating_sub(text_h) / 2
+            + i as u32 * header.line_h
+            + (header.line_h as f32 - line.height()).max(0.) as u32 / 2;
+        let anchor = (center_x, y_offset as f32);
+        let text_style = style.text_style(line.font_size, style.text_color);
+        fonts.draw_line(group_pic, line, anchor, Align::Center, clip, &text_style)?;
+    }
+    if let Some(subtitle) = &header.subtitle {
+        // dim the subtitle below the title
+        let mut color = style.text_color;
+        color.0[3] = (color.0[3] as u32 * 3 / 4) as u8;
+        let y_offset = top + header_h.saturating_sub(text_h) / 2 + text_h - subtitle_h;
+        let anchor = (center_x, y_offset as f32);
+        let text_style = style.text_style(subtitle.font_size, color);
+        fonts.draw_line(
+            group_pic,
+            subtitle,
+            anchor,
+            Align::Center,
+            clip,
+            &text_style,
+        )?;
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use glyph_brush_layout::FontId;
     use image::ImageBuffer;
-    use rand::prelude::*;
     use std::fs;
-    use std::path::Path;
+    use std::ops::Range;
+    use std::path::{Path, PathBuf};
 
-    fn generate_random_test_avatars_in_dir_with_size<P>(dir: P, size: u32)
-    where
-        P: AsRef<Path>,
-    {
-        let mut rng = rand::thread_rng();
-        for i in 0..99 {
-            let gray: u8 = rng.gen();
-            let img = ImageBuffer::from_pixel(size, size, image::Luma([gray]));
-            let img_path = dir.as_ref().join(format!("{}.png", i));
-            img.save(img_path).unwrap();
+    /// Set to rewrite the golden images with the current output instead of comparing against them
+    const UPDATE_GOLDEN_VAR: &str = "GROUPIC_UPDATE_GOLDEN";
+    /// Largest YIQ color difference between two pixels that still looks the same, as a fraction
+    /// of the largest possible difference
+    const GOLDEN_PIXEL_THRESHOLD: f32 = 0.1;
+    /// Fraction of pixels that may look different from the golden image, such as anti-aliased
+    /// edges rasterized slightly differently
+    const GOLDEN_MAX_DIFF_RATIO: f32 = 0.001;
+
+    /// Deterministic random numbers for fixtures, independent of the version of `rand`
+    struct SplitMix64(u64);
+
+    impl SplitMix64 {
+        fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        }
+
+        fn next_u8(&mut self) -> u8 {
+            (self.next_u64() >> 56) as u8
         }
     }
 
-    #[test]
-    fn generate_random_test_avatars() {
-        let temp_dir = Path::new("tmp");
-        if !temp_dir.exists() {
-            fs::create_dir(temp_dir).unwrap();
+    /// Avatars split into two random colors above and below a diagonal, the same for the same
+    /// seed
+    fn fixture_avatars(n: usize, size: u32, seed: u64) -> Vec<RgbaImage> {
+        let mut rng = SplitMix64(seed);
+        (0..n)
+            .map(|_| {
+                let above = Rgba([rng.next_u8(), rng.next_u8(), rng.next_u8(), 255]);
+                let below = Rgba([rng.next_u8(), rng.next_u8(), rng.next_u8(), 255]);
+                ImageBuffer::from_fn(size, size, |x, y| if x > y { above } else { below })
+            })
+            .collect()
+    }
+
+    /// Directory of its own for a test, removed when dropped
+    struct FixtureDir(PathBuf);
+
+    impl FixtureDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("groupic-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("avatars")).unwrap();
+            Self(dir)
+        }
+
+        /// Save `avatars` into the avatars directory, named so that they sort in order
+        fn with_avatars(self, avatars: &[RgbaImage]) -> Self {
+            for (i, avatar) in avatars.iter().enumerate() {
+                avatar
+                    .save(self.avatars().join(format!("{:03}.png", i)))
+                    .unwrap();
+            }
+            self
+        }
+
+        fn avatars(&self) -> PathBuf {
+            self.0.join("avatars")
+        }
+    }
+
+    impl Drop for FixtureDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Color difference of two pixels blended over white, weighted in YIQ space like pixelmatch
+    fn yiq_difference(a: &Rgba<u8>, b: &Rgba<u8>) -> f32 {
+        let yiq = |p: &Rgba<u8>| {
+            let alpha = p.0[3] as f32 / 255.;
+            let [r, g, b] = [0, 1, 2].map(|c| 255. + (p.0[c] as f32 - 255.) * alpha);
+            (
+                r * 0.299 + g * 0.587 + b * 0.114,
+                r * 0.596 - g * 0.274 - b * 0.322,
+                r * 0.211 - g * 0.523 + b * 0.312,
+            )
+        };
+        let ((y0, i0, q0), (y1, i1, q1)) = (yiq(a), yiq(b));
+        let (dy, di, dq) = (y0 - y1, i0 - i1, q0 - q1);
+        0.5053 * dy * dy + 0.299 * di * di + 0.1957 * dq * dq
+    }
+
+    /// Compare `actual` against the golden image `name`, or replace the golden image with it if
+    /// `GROUPIC_UPDATE_GOLDEN` is set
+    ///
+    /// Golden images live in `src/gen_pic/golden`. On a mismatch the actual image is saved next
+    /// to the temporary directory for a look.
+    fn assert_matches_golden(name: &str, actual: &RgbaImage) {
+        let golden_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/gen_pic/golden")
+            .join(format!("{}.png", name));
+        if std::env::var_os(UPDATE_GOLDEN_VAR).is_some() {
+            fs::create_dir_all(golden_path.parent().unwrap()).unwrap();
+            actual.save(&golden_path).unwrap();
+            return;
+        }
+        let golden = image::open(&golden_path)
+            .unwrap_or_else(|e| {
+                panic!(
+                    "failed to open {}, set {} to create it: {}",
+                    golden_path.display(),
+                    UPDATE_GOLDEN_VAR,
+                    e
+                )
+            })
+            .into_rgba8();
+
+        let actual_path = std::env::temp_dir().join(format!("groupic-{}.actual.png", name));
+        if golden.dimensions() != actual.dimensions() {
+            actual.save(&actual_path).unwrap();
+            panic!(
+                "{} is {:?} but the golden image is {:?}, see {}",
+                name,
+                actual.dimensions(),
+                golden.dimensions(),
+                actual_path.display()
+            );
         }
-        let avatar_dir = Path::new("tmp/test_avatars");
-        if !avatar_dir.exists() {
-            fs::create_dir(avatar_dir).unwrap();
+        // the largest difference is between black and white
+        let max_difference = yiq_difference(&Rgba([0, 0, 0, 255]), &Rgba([255, 255, 255, 255]));
+        let threshold = max_difference * GOLDEN_PIXEL_THRESHOLD * GOLDEN_PIXEL_THRESHOLD;
+        let different = golden
+            .pixels()
+            .zip(actual.pixels())
+            .filter(|(g, a)| yiq_difference(g, a) > threshold)
+            .count();
+        let ratio = different as f32 / (golden.width() * golden.height()) as f32;
+        if ratio > GOLDEN_MAX_DIFF_RATIO {
+            actual.save(&actual_path).unwrap();
+            panic!(
+                "{} differs from the golden image in {} pixels, see {}",
+                name,
+                different,
+                actual_path.display()
+            );
         }
-        generate_random_test_avatars_in_dir_with_size(avatar_dir, 128);
+    }
+
+    /// Save `avatars` into a directory of their own, run `generate_group_pic` on it, and check that
+    /// the saved group picture is the one rendered in memory
+    fn assert_generates_group_pic(
+        name: &str,
+        avatars: &[RgbaImage],
+        num_of_avatars_in_a_row: Option<u32>,
+        header_text: &str,
+    ) -> RgbaImage {
+        let dir = FixtureDir::new(name).with_avatars(avatars);
+        let out = dir.0.join("group_pic.png");
+        generate_group_pic(dir.avatars(), &out, num_of_avatars_in_a_row, header_text).unwrap();
+        let group_pic = image::open(&out).unwrap().into_rgba8();
+        let config = GroupPicConfig {
+            header_text: header_text.into(),
+            num_of_avatars_in_a_row,
+            ..Default::default()
+        };
+        assert_eq!(group_pic, render_group_pic(avatars, &config).unwrap());
+        group_pic
     }
 
     #[test]
     fn generate_full_group_pic() {
-        generate_group_pic(
-            "tmp/test_avatars",
-            "tmp/example_group_pic.png",
-            Some(5),
-            "niji3rd-live-day1",
-        );
+        let avatars = fixture_avatars(99, 128, 1);
+        let group_pic = assert_generates_group_pic("full", &avatars, Some(5), "niji3rd-live-day1");
+        assert_eq!(group_pic.dimensions(), (128 * 5, 64 + 128 * 20));
     }
 
     #[test]
     fn only_one_avatar() {
-        generate_group_pic(
-            "tmp/test_one_avatar",
-            "tmp/one_avatar_group_pic.png",
-            Some(5),
-            "niji3rd-live-day1",
-        );
+        let avatars = fixture_avatars(1, 128, 2);
+        let group_pic = assert_generates_group_pic("one", &avatars, Some(5), "niji3rd-live-day1");
+        assert_eq!(group_pic.dimensions(), (128 * 5, 64 + 128));
     }
 
     #[test]
     fn only_one_avatar_with_kana_kanji() {
-        generate_group_pic(
-            "tmp/test_one_avatar",
-            "tmp/one_avatar_with_kana_kanji.png",
-            Some(5),
-            "ラブライブ!虹ヶ咲3rdライブ1日目",
-        );
+        let avatars = fixture_avatars(1, 128, 2);
+        let header_text = "ラブライブ!虹ヶ咲3rdライブ1日目";
+        assert_generates_group_pic("kana-kanji", &avatars, Some(5), header_text);
     }
 
     #[test]
     fn square_group_pic() {
-        generate_group_pic(
-            "tmp/test_avatars",
-            "tmp/example_group_pic.png",
-            None,
-            "niji3rd-live-day1",
-        );
+        let avatars = fixture_avatars(99, 128, 1);
+        let group_pic = assert_generates_group_pic("square", &avatars, None, "niji3rd-live-day1");
+        // 9 columns for 99 avatars
+        assert_eq!(group_pic.dimensions(), (128 * 9, 64 + 128 * 11));
     }
 
-    /// Doesn't support emoji yet
     #[test]
     fn only_one_avatar_with_emoji() {
-        generate_group_pic(
-            "tmp/test_one_avatar",
-            "tmp/one_avatar_with_emoji.png",
-            Some(5),
-            "🔥👀🌾🍛",
+        let avatars = fixture_avatars(1, 128, 2);
+        assert_generates_group_pic("emoji", &avatars, Some(5), "🔥👀🌾🍛");
+    }
+
+    #[test]
+    fn golden_mask_shapes() {
+        let avatars = fixture_avatars(3, 96, 3);
+        for mask_shape in MaskShape::ALL {
+            let config = GroupPicConfig {
+                tile_size: 64,
+                mask_shape,
+                ..Default::default()
+            };
+            let name = format!("mask_{}", mask_shape.as_ref());
+            assert_matches_golden(&name, &render_group_pic(&avatars, &config).unwrap());
+        }
+    }
+
+    #[test]
+    fn golden_layouts() {
+        let avatars = fixture_avatars(12, 64, 4);
+        for layout in Layout::ALL {
+            let config = GroupPicConfig {
+                tile_size: 64,
+                layout,
+                style: Theme::DiscordLight.style(),
+                ..Default::default()
+            };
+            let name = format!("layout_{}", layout.as_ref());
+            assert_matches_golden(&name, &render_group_pic(&avatars, &config).unwrap());
+        }
+    }
+
+    #[test]
+    fn golden_badges_and_rings() {
+        let avatars = fixture_avatars(6, 128, 5);
+        let badges = [
+            VoiceBadges::default(),
+            VoiceBadges {
+                self_mute: true,
+                ..Default::default()
+            },
+            VoiceBadges {
+                self_mute: true,
+                self_deaf: true,
+                ..Default::default()
+            },
+            VoiceBadges {
+                server_mute: true,
+                ..Default::default()
+            },
+            VoiceBadges {
+                video: true,
+                self_mute: true,
+                ..Default::default()
+            },
+            VoiceBadges {
+                video: true,
+                server_mute: true,
+                self_deaf: true,
+                ..Default::default()
+            },
+        ];
+        let config = GroupPicConfig {
+            num_of_avatars_in_a_row: Some(3),
+            badges: Some(badges.to_vec()),
+            rings: Some(vec![Rgba([237, 66, 69, 255]); 6]),
+            ring_thickness: 6,
+            style: Theme::AmoledBlack.style(),
+            ..Default::default()
+        };
+        assert_matches_golden(
+            "badges_and_rings",
+            &render_group_pic(&avatars, &config).unwrap(),
+        );
+    }
+
+    #[test]
+    fn golden_background() {
+        let avatars = fixture_avatars(7, 128, 6);
+        let image = ImageBuffer::from_fn(320, 180, |x, y| {
+            if (x / 20 + y / 20) % 2 == 0 {
+                Rgba([250, 200, 40, 255])
+            } else {
+                Rgba([40, 160, 90, 255])
+            }
+        });
+        let config = GroupPicConfig {
+            background: Some(Background::new(image)),
+            transparent: true,
+            ..Default::default()
+        };
+        assert_matches_golden("background", &render_group_pic(&avatars, &config).unwrap());
+    }
+
+    /// Header in the Latin font
+    ///
+    /// The glyphs come from the font in the repository root, so this has to be regenerated
+    /// whenever the font is updated. Other scripts are checked by the fonts and glyphs they land
+    /// on in the `render_*_header` tests.
+    #[test]
+    fn golden_header() {
+        let avatars = fixture_avatars(3, 96, 28);
+        let config = GroupPicConfig {
+            tile_size: 64,
+            header_text: "Nijigasaki 3rd Live".into(),
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        assert_matches_golden("header_latin", &group_pic);
+    }
+
+    #[test]
+    fn golden_tolerates_small_differences() {
+        let a = Rgba([100, 150, 200, 255]);
+        assert_eq!(yiq_difference(&a, &a), 0.);
+        let max_difference = yiq_difference(&Rgba([0, 0, 0, 255]), &Rgba([255, 255, 255, 255]));
+        let threshold = max_difference * GOLDEN_PIXEL_THRESHOLD * GOLDEN_PIXEL_THRESHOLD;
+        assert!(yiq_difference(&a, &Rgba([102, 151, 198, 255])) < threshold);
+        assert!(yiq_difference(&a, &Rgba([200, 150, 100, 255])) > threshold);
+        // fully transparent pixels look the same whatever their color
+        assert_eq!(
+            yiq_difference(&Rgba([0, 0, 0, 0]), &Rgba([255, 0, 0, 0])),
+            0.
+        );
+    }
+
+    #[test]
+    fn render_in_memory() {
+        let avatars = fixture_avatars(7, 96, 7);
+        let config = GroupPicConfig {
+            header_text: "niji3rd-live-day1".into(),
+            num_of_avatars_in_a_row: Some(5),
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        assert_eq!(group_pic.dimensions(), (128 * 5, 64 + 128 * 2));
+        let png = encode_group_pic_png(&group_pic).unwrap();
+        assert_eq!(
+            image::load_from_memory(&png).unwrap().into_rgba8(),
+            group_pic
+        );
+    }
+
+    #[test]
+    fn blend_over_keeps_opaque_pixels_opaque() {
+        let src = |a: u8| Rgba([200, 100, 50, a]);
+        for a in 0..=255 {
+            let mut opaque = Rgba([30, 60, 90, 255]);
+            blend_over(&mut opaque, &src(a));
+            assert_eq!(opaque.0[3], 255, "coverage {}", a);
+            let mut transparent = Rgba([0, 0, 0, 0]);
+            blend_over(&mut transparent, &src(a));
+            assert_eq!(
+                transparent,
+                if a == 0 { Rgba([0, 0, 0, 0]) } else { src(a) }
+            );
+        }
+        let mut half = Rgba([0, 0, 0, 255]);
+        blend_over(&mut half, &Rgba([255, 255, 255, 128]));
+        assert_eq!(half, Rgba([128, 128, 128, 255]));
+    }
+
+    #[test]
+    fn opaque_theme_renders_opaque() {
+        let avatars = fixture_avatars(5, 128, 29);
+        let config = GroupPicConfig {
+            header_text: "niji3rd-live-day1 🌈".into(),
+            subtitle: Some("2022-02-19 · 5 members".into()),
+            captions: Some(vec!["Ayumu".into(); 5]),
+            badges: Some(vec![
+                VoiceBadges {
+                    self_mute: true,
+                    ..Default::default()
+                };
+                5
+            ]),
+            rings: Some(vec![Rgba([237, 66, 69, 255]); 5]),
+            mask_shape: MaskShape::Heart,
+            footer: Some("Spring Live".into()),
+            style: GroupPicStyle::DISCORD_DARK.with_text_effect(TextEffect::Shadow),
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        assert!(group_pic.pixels().all(|p| p.0[3] == 255));
+    }
+
+    #[test]
+    fn render_with_empty_header() {
+        let avatars = fixture_avatars(1, 128, 8);
+        let group_pic = render_group_pic(&avatars, &GroupPicConfig::default()).unwrap();
+        assert_eq!(group_pic.dimensions(), (128 * 5, 64 + 128));
+    }
+
+    #[test]
+    fn render_emoji_header() {
+        let avatars = fixture_avatars(1, 128, 9);
+        let config = GroupPicConfig {
+            header_text: "🔥👀🌾🍛 oats curry".into(),
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        assert_eq!(group_pic.dimensions(), (128 * 5, 64 + 128));
+        // the theme and the text are gray, so colors in the header come from the emoji bitmaps
+        let colored = group_pic
+            .rows()
+            .take(64)
+            .flatten()
+            .filter(|p| {
+                let [r, g, b, _] = p.0;
+                r.max(g).max(b) - r.min(g).min(b) > 64
+            })
+            .count();
+        assert!(
+            colored > 200,
+            "only {} colored pixels in the header",
+            colored
+        );
+    }
+
+    /// Font of the run covering the whole of `word` in `text`, if there is one
+    fn font_of_word(text: &str, word: &str) -> Option<FontId> {
+        let start = text.find(word).unwrap();
+        let fonts = Fonts::get().unwrap();
+        fonts
+            .split_font_runs(text)
+            .into_iter()
+            .find(|(range, _)| range.start <= start && start + word.len() <= range.end)
+            .map(|(_, font_id)| font_id)
+    }
+
+    #[test]
+    fn render_multilingual_header() {
+        let avatars = fixture_avatars(1, 128, 10);
+        let text = "虹ヶ咲 Привет 안녕 สวัสดี ⠓⠊";
+        let config = GroupPicConfig {
+            header_text: text.into(),
+            ..Default::default()
+        };
+        render_group_pic(&avatars, &config).unwrap();
+        // indices into the fallback chain listed in the README
+        assert_eq!(font_of_word(text, "虹ヶ咲"), Some(FontId(0)));
+        assert_eq!(font_of_word(text, "안녕"), Some(FontId(2)));
+        assert_eq!(font_of_word(text, "สวัสดี"), Some(FontId(6)));
+        assert_eq!(font_of_word(text, "⠓⠊"), Some(FontId(8)));
+    }
+
+    #[test]
+    fn render_complex_script_header() {
+        let avatars = fixture_avatars(1, 128, 11);
+        let text = "حفلة الليلة नमस्ते दुनिया";
+        let config = GroupPicConfig {
+            header_text: text.into(),
+            ..Default::default()
+        };
+        render_group_pic(&avatars, &config).unwrap();
+        assert_eq!(font_of_word(text, "حفلة"), Some(FontId(3)));
+        assert_eq!(font_of_word(text, "नमस्ते"), Some(FontId(5)));
+
+        let fonts = Fonts::get().unwrap();
+        let glyph_at = |text: &str, byte_index: usize| {
+            let shaped = fonts.shape(text, 54.);
+            let glyph = shaped.glyphs.iter().find(|g| g.byte_index == byte_index);
+            glyph.unwrap().glyph.id
+        };
+        // the first letter of an Arabic word takes its initial form to join the next one
+        assert_ne!(glyph_at("حفلة", 0), glyph_at("ح", 0));
+        // lam and alef form a ligature
+        assert_eq!(fonts.shape("لا", 54.).glyphs.len(), 1);
+        // the virama joins the consonants around it into a conjunct
+        assert!(fonts.shape("स्त", 54.).glyphs.len() < "स्त".chars().count());
+    }
+
+    #[test]
+    fn header_shrinks_to_fit() {
+        let fonts = Fonts::get().unwrap();
+        let config = GroupPicConfig::default();
+        let text = "niji3rd-live-day1 after party";
+        let header = layout_header(text, 128 * 5, &config, fonts);
+        assert_eq!(header.lines.len(), 1);
+        assert!(header.lines[0].width <= (128 * 5 - 2 * HEADER_MARGIN) as f32);
+        assert_eq!(header.header_h, config.header_h);
+    }
+
+    #[test]
+    fn header_wraps_and_ellipsizes() {
+        let fonts = Fonts::get().unwrap();
+        let config = GroupPicConfig::default();
+        let max_w = (128 * 5 - 2 * HEADER_MARGIN) as f32;
+
+        let text = "Love Live! Nijigasaki High School Idol Club 3rd Live School Idol Festival";
+        let header = layout_header(text, 128 * 5, &config, fonts);
+        assert_eq!(header.lines.len(), 2);
+        assert!(header.lines.iter().all(|l| l.width <= max_w));
+        assert_eq!(header.header_h, config.header_h + header.line_h);
+
+        let text = text.repeat(4);
+        let header = layout_header(&text, 128 * 5, &config, fonts);
+        assert_eq!(header.lines.len(), config.header_max_lines as usize);
+        assert!(header.lines.iter().all(|l| l.width <= max_w));
+
+        // a line without spaces still breaks between graphemes
+        let text =
+            "ラブライブ虹ヶ咲学園スクールアイドル同好会3rdライブスクールアイドルフェスティバル";
+        let header = layout_header(text, 128 * 5, &config, fonts);
+        assert!(header.lines.iter().all(|l| l.width <= max_w));
+    }
+
+    #[test]
+    fn header_with_icon_and_subtitle() {
+        let fonts = Fonts::get().unwrap();
+        let text = "niji3rd-live-day1";
+        let config = GroupPicConfig::default();
+        let plain = layout_header(text, 128 * 5, &config, fonts);
+        let config = GroupPicConfig {
+            header_icon: Some(ImageBuffer::from_pixel(64, 64, Rgba([255, 0, 0, 255]))),
+            subtitle: Some("2022-02-05 · 12 members · Nijigasaki".into()),
+            ..Default::default()
+        };
+        let header = layout_header(text, 128 * 5, &config, fonts);
+        let subtitle_h = header.subtitle.as_ref().unwrap().height().ceil() as u32;
+        assert_eq!(
+            header.icon_size,
+            config.header_h + subtitle_h - 2 * HEADER_MARGIN
+        );
+        assert_eq!(header.header_h, plain.header_h + subtitle_h);
+
+        // the icon sits left of the text, vertically centered in the header
+        let avatars = fixture_avatars(5, 128, 12);
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        let icon_rows = (header.header_h - header.icon_size) / 2;
+        let red =
+            |y: u32| (0..128 * 5).find(|&x| *group_pic.get_pixel(x, y) == Rgba([255, 0, 0, 255]));
+        let center = red(icon_rows + header.icon_size / 2).unwrap();
+        assert!(center < 128 * 5 / 2);
+        assert_eq!(red(icon_rows.saturating_sub(2)), None);
+    }
+
+    #[test]
+    fn render_header_alone() {
+        let avatars = fixture_avatars(5, 128, 13);
+        let config = GroupPicConfig {
+            header_text: "niji3rd-live-day1".into(),
+            subtitle: Some("2022-02-05".into()),
+            ..Default::default()
+        };
+        let header = render_header(&config, 128 * 5).unwrap();
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        let (w, h) = header.dimensions();
+        assert_eq!(w, group_pic.width());
+        assert!(header
+            .enumerate_pixels()
+            .all(|(x, y, p)| group_pic.get_pixel(x, y) == p));
+        assert!(h > 64);
+    }
+
+    #[test]
+    fn render_captions() {
+        let avatars = fixture_avatars(7, 128, 14);
+        let config = GroupPicConfig {
+            header_text: "niji3rd-live-day1".into(),
+            num_of_avatars_in_a_row: Some(5),
+            captions: Some(
+                vec![
+                    "あゆぴょん",
+                    "kasukasu",
+                    "Shizuku Osaka the Third of Her Name",
+                    "",
+                ]
+                .into_iter()
+                .cycle()
+                .take(7)
+                .map(String::from)
+                .collect(),
+            ),
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        let (w, h) = group_pic.dimensions();
+        assert_eq!(w, 128 * 5);
+        assert!(h > 64 + 128 * 2);
+    }
+
+    #[test]
+    fn render_themes() {
+        let avatars = fixture_avatars(7, 128, 15);
+        for theme in Theme::ALL {
+            assert_eq!(theme.as_ref().parse::<Theme>().unwrap(), theme);
+            let style = theme.style();
+            let config = GroupPicConfig {
+                header_text: "niji3rd-live-day1".into(),
+                num_of_avatars_in_a_row: Some(5),
+                style,
+                ..Default::default()
+            };
+            let group_pic = render_group_pic(&avatars, &config).unwrap();
+            let (w, h) = group_pic.dimensions();
+            assert_eq!(w, 128 * 5 + 4 * style.tile_gap + 2 * style.padding);
+            assert_eq!(h, 64 + 128 * 2 + style.tile_gap + style.padding);
+            assert_eq!(*group_pic.get_pixel(0, 0), style.header_color);
+            assert_eq!(*group_pic.get_pixel(w - 1, h - 1), style.background_color);
+        }
+        assert!(matches!(
+            "sepia".parse::<Theme>(),
+            Err(GenPicError::UnknownTheme(_))
+        ));
+    }
+
+    #[test]
+    fn text_effects() {
+        for effect in TextEffect::ALL {
+            assert_eq!(effect.as_ref().parse::<TextEffect>().unwrap(), effect);
+        }
+        assert!(matches!(
+            "glow".parse::<TextEffect>(),
+            Err(GenPicError::UnknownTextEffect(_))
+        ));
+
+        // effects contrast with the text color
+        let dark = GroupPicStyle::DISCORD_DARK.with_text_effect(TextEffect::Shadow);
+        assert_eq!(dark.text_shadow.unwrap().color.0[..3], [0, 0, 0]);
+        let light = GroupPicStyle::DISCORD_LIGHT.with_text_effect(TextEffect::Stroke);
+        assert_eq!(light.text_stroke.unwrap().color, Rgba([255, 255, 255, 255]));
+        let none = light.with_text_effect(TextEffect::None);
+        assert_eq!(none, GroupPicStyle::DISCORD_LIGHT);
+
+        // the bar darkens the header behind the text, but not its edges
+        let avatars = fixture_avatars(5, 128, 16);
+        let header_row = |style: GroupPicStyle| {
+            let config = GroupPicConfig {
+                header_text: "niji3rd".into(),
+                style,
+                ..Default::default()
+            };
+            let group_pic = render_group_pic(&avatars, &config).unwrap();
+            let y = config.header_h / 2;
+            (0..group_pic.width())
+                .map(|x| *group_pic.get_pixel(x, y))
+                .collect::<Vec<_>>()
+        };
+        let header_color = GroupPicStyle::DISCORD_DARK.header_color;
+        let differs = |row: &[Rgba<u8>]| row.iter().filter(|&&p| p != header_color).count();
+        let plain = header_row(GroupPicStyle::DISCORD_DARK);
+        let barred = header_row(GroupPicStyle::DISCORD_DARK.with_text_effect(TextEffect::Bar));
+        assert_eq!(barred[0], header_color);
+        assert!(differs(&barred) > 2 * differs(&plain));
+
+        for effect in TextEffect::ALL {
+            let config = GroupPicConfig {
+                header_text: "niji3rd-live-day1".into(),
+                captions: Some(vec!["Ayumu".into(); 5]),
+                style: GroupPicStyle::default().with_text_effect(effect),
+                ..Default::default()
+            };
+            render_group_pic(&avatars, &config).unwrap();
+        }
+    }
+
+    #[test]
+    fn render_footer_and_overlays() {
+        let avatars = fixture_avatars(5, 128, 17);
+        let red = Rgba([255, 0, 0, 255]);
+        let config = GroupPicConfig {
+            header_text: "niji3rd-live-day1".into(),
+            footer: Some("Spring Live 2022".into()),
+            overlays: vec![Overlay {
+                anchor: OverlayAnchor::BottomRight,
+                margin: 4,
+                ..Overlay::new(ImageBuffer::from_pixel(8, 8, red))
+            }],
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        let (w, h) = group_pic.dimensions();
+        assert_eq!(w, 128 * 5);
+        assert!(h > 64 + 128);
+        // the footer bar is below the avatars, with the overlay in its corner
+        let header_color = GroupPicStyle::default().header_color;
+        assert_eq!(*group_pic.get_pixel(0, h - 1), header_color);
+        assert_eq!(*group_pic.get_pixel(w - 5, h - 5), red);
+        assert_eq!(*group_pic.get_pixel(w - 4, h - 4), header_color);
+        assert_eq!(*group_pic.get_pixel(w - 13, h - 13), header_color);
+
+        // a filling overlay covers everything, half transparent
+        let config = GroupPicConfig {
+            overlays: vec![Overlay {
+                anchor: OverlayAnchor::Fill,
+                opacity: 0.5,
+                ..Overlay::new(ImageBuffer::from_pixel(4, 4, red))
+            }],
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        assert!(group_pic.pixels().all(|p| p.0[0] >= 127));
+
+        for anchor in OverlayAnchor::ALL {
+            assert_eq!(anchor.as_ref().parse::<OverlayAnchor>().unwrap(), anchor);
+        }
+        assert!(matches!(
+            "middle".parse::<OverlayAnchor>(),
+            Err(GenPicError::UnknownOverlayAnchor(_))
+        ));
+    }
+
+    #[test]
+    fn mask_shapes() {
+        for shape in MaskShape::ALL {
+            assert_eq!(shape.as_ref().parse::<MaskShape>().unwrap(), shape);
+            let mask = render_mask(shape, 128);
+            // the center is covered, the corners are not, and the edges are anti-aliased
+            assert_eq!(mask.get_pixel(64, 64).0[0], 255);
+            assert_eq!(mask.get_pixel(0, 0).0[0], 0);
+            assert_eq!(mask.get_pixel(127, 127).0[0], 0);
+            assert!(mask.pixels().any(|p| p.0[0] > 0 && p.0[0] < 255));
+        }
+        assert!(matches!(
+            "star".parse::<MaskShape>(),
+            Err(GenPicError::UnknownMaskShape(_))
+        ));
+    }
+
+    #[test]
+    fn render_transparent() {
+        let avatars = vec![ImageBuffer::from_pixel(128, 128, Rgba([200, 100, 50, 255])); 2];
+        let config = GroupPicConfig {
+            header_text: "niji3rd-live-day1".into(),
+            num_of_avatars_in_a_row: Some(5),
+            transparent: true,
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        // corners of the header, of an avatar and of the empty tiles are transparent
+        assert_eq!(group_pic.get_pixel(0, 0).0[3], 0);
+        assert_eq!(group_pic.get_pixel(0, 64).0[3], 0);
+        assert_eq!(group_pic.get_pixel(128 * 5 - 1, 64 + 127).0[3], 0);
+        // the avatar and the header text are opaque
+        assert_eq!(*group_pic.get_pixel(64, 64 + 64), avatars[0][(64, 64)]);
+        assert!(group_pic
+            .rows()
+            .take(64)
+            .flatten()
+            .any(|p| p.0[3] == 255 && p.0[..3] == [240, 240, 240]));
+        // the anti-aliased edge of an avatar is translucent in the color of the avatar
+        let edge = (0..128)
+            .map(|x| group_pic.get_pixel(x, 64 + 16))
+            .find(|p| p.0[3] > 0)
+            .unwrap();
+        assert!(edge.0[3] < 255);
+        assert_eq!(edge.0[..3], avatars[0][(64, 64)].0[..3]);
+    }
+
+    #[test]
+    fn resize_without_dark_fringes() {
+        // an opaque red square in the middle of a transparent black avatar
+        let avatar = ImageBuffer::from_fn(256, 256, |x, y| {
+            if (64..192).contains(&x) && (64..192).contains(&y) {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        });
+        let resized = resize_premultiplied(&avatar, 128, 128);
+        assert!(resized
+            .pixels()
+            .filter(|p| p.0[3] > 16)
+            .all(|p| p.0[0] > 240 && p.0[1] < 16 && p.0[2] < 16));
+    }
+
+    #[test]
+    fn render_badges() {
+        let avatars = vec![ImageBuffer::from_pixel(128, 128, Rgba([0, 0, 255, 255])); 2];
+        let badges = VoiceBadges {
+            server_mute: true,
+            streaming: true,
+            ..Default::default()
+        };
+        let config = GroupPicConfig {
+            badges: Some(vec![badges, VoiceBadges::default()]),
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        // the left edge of the server mute badge is red, while the second avatar has the masked
+        // out corner there
+        let y = 64 + 128 - BADGE_BORDER - BADGE_SIZE / 2;
+        let x = 128 - BADGE_BORDER - BADGE_SIZE + 2;
+        assert_eq!(*group_pic.get_pixel(x, y), Badge::ServerMute.color());
+        assert_eq!(
+            *group_pic.get_pixel(128 + x, y),
+            GroupPicStyle::DISCORD_DARK.background_color
+        );
+        assert_eq!(badges.badges(), vec![Badge::ServerMute, Badge::Streaming]);
+    }
+
+    fn animated_test_avatar(colors: &[[u8; 3]], delay_ms: u32) -> Vec<Frame> {
+        colors
+            .iter()
+            .map(|&[r, g, b]| {
+                let buffer = ImageBuffer::from_pixel(128, 128, Rgba([r, g, b, 255]));
+                Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(delay_ms, 1))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn render_animated() {
+        let avatars = vec![
+            animated_test_avatar(&[[255, 0, 0], [0, 255, 0], [0, 0, 255]], 100),
+            animated_test_avatar(&[[255, 255, 0], [0, 255, 255]], 150),
+            animated_test_avatar(&[[255, 255, 255]], 0),
+        ];
+        let config = GroupPicConfig::default();
+        let gif =
+            render_animated_group_pic(&avatars, &config, &AnimationBudget::default()).unwrap();
+        let frames = decode_avatar_frames(&gif).unwrap();
+        // the first avatar changes at 100 and 200 ms, the second at 150 ms
+        assert_eq!(frames.len(), 4);
+        let delays: Vec<_> = frames.iter().map(frame_delay_ms).collect();
+        assert_eq!(delays, vec![100, 50, 50, 100]);
+        let center = |frame: &Frame, i: u32| frame.buffer().get_pixel(64 + 128 * i, 64 + 64).0;
+        assert_eq!(center(&frames[0], 0)[..3], [255, 0, 0]);
+        assert_eq!(center(&frames[2], 0)[..3], [0, 255, 0]);
+        assert_eq!(center(&frames[2], 1)[..3], [0, 255, 255]);
+        assert_eq!(center(&frames[3], 0)[..3], [0, 0, 255]);
+        assert_eq!(center(&frames[3], 2)[..3], [255, 255, 255]);
+    }
+
+    #[test]
+    fn render_animated_within_budget() {
+        let colors: Vec<[u8; 3]> = (0..40).map(|i| [i * 6, 255 - i * 6, 0]).collect();
+        let avatars = vec![animated_test_avatar(&colors, 50)];
+        let config = GroupPicConfig::default();
+        let budget = AnimationBudget {
+            max_frames: 10,
+            ..Default::default()
+        };
+        let gif = render_animated_group_pic(&avatars, &config, &budget).unwrap();
+        assert!(decode_avatar_frames(&gif).unwrap().len() <= 10);
+
+        let budget = AnimationBudget {
+            max_frames: 4,
+            max_duration_ms: 10_000,
+            max_bytes: 100,
+        };
+        assert!(matches!(
+            render_animated_group_pic(&avatars, &config, &budget),
+            Err(GenPicError::OverBudget { max_bytes: 100, .. })
+        ));
+        assert!(matches!(
+            render_animated_group_pic(&[vec![]], &config, &budget),
+            Err(GenPicError::NoFrames { avatar: 0 })
+        ));
+    }
+
+    #[test]
+    fn redraw_only_the_changed_tiles() {
+        let avatars = fixture_avatars(12, 64, 7);
+        let changed = fixture_avatars(12, 64, 8);
+        let animated = [4, 9];
+        let frame: Vec<RgbaImage> = (0..12)
+            .map(|i| {
+                let avatars = if animated.contains(&i) {
+                    &changed
+                } else {
+                    &avatars
+                };
+                avatars[i].clone()
+            })
+            .collect();
+        for layout in Layout::ALL {
+            let config = GroupPicConfig {
+                tile_size: 64,
+                layout,
+                captions: Some((0..12).map(|i| format!("member {}", i)).collect()),
+                badges: Some(vec![
+                    VoiceBadges {
+                        self_mute: true,
+                        ..Default::default()
+                    };
+                    12
+                ]),
+                rings: Some(vec![Rgba([237, 66, 69, 255]); 12]),
+                style: GroupPicStyle::default().with_text_effect(TextEffect::Shadow),
+                footer: Some("Spring Live 2022".into()),
+                overlays: vec![Overlay {
+                    anchor: OverlayAnchor::Fill,
+                    opacity: 0.25,
+                    ..Overlay::new(fixture_avatars(1, 16, 9).remove(0))
+                }],
+                ..Default::default()
+            };
+            let (canvas, mut still) = GroupPicCanvas::new(12, &config).unwrap();
+            let mut redrawn: Vec<bool> = (0..12).map(|i| animated.contains(&i)).collect();
+            canvas.spread_redrawn(&mut redrawn);
+            let still_tiles: Vec<_> = (0..12)
+                .filter(|&i| !redrawn[i])
+                .map(|i| (i, &avatars[i]))
+                .collect();
+            let frame_tiles: Vec<_> = (0..12)
+                .filter(|&i| redrawn[i])
+                .map(|i| (i, &frame[i]))
+                .collect();
+            canvas.draw_tiles(&mut still, &still_tiles).unwrap();
+            canvas.draw_tiles(&mut still, &frame_tiles).unwrap();
+            canvas.draw_over_tiles(&mut still).unwrap();
+            assert_eq!(still, render_group_pic(&frame, &config).unwrap());
+            if layout == Layout::Grid {
+                assert_eq!(redrawn.iter().filter(|&&r| r).count(), animated.len());
+            }
+        }
+    }
+
+    #[test]
+    fn encode_formats() {
+        let config = GroupPicConfig {
+            header_text: "niji3rd-live-day1".into(),
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&fixture_avatars(7, 128, 18), &config).unwrap();
+        for format in OutputFormat::ALL {
+            let bytes = encode_group_pic(&group_pic, format).unwrap();
+            // image 0.24 cannot decode every WebP, such as lossy WebP with alpha
+            let decoded = match format {
+                OutputFormat::WebPLossless | OutputFormat::WebP(_) => {
+                    let features = webp::BitstreamFeatures::new(&bytes).unwrap();
+                    // the picture is opaque, so the encoder has no alpha to keep
+                    assert!(!features.has_alpha());
+                    webp::Decoder::new(&bytes)
+                        .decode()
+                        .unwrap()
+                        .to_image()
+                        .into_rgba8()
+                }
+                _ => image::load_from_memory(&bytes).unwrap().into_rgba8(),
+            };
+            assert_eq!(decoded.dimensions(), group_pic.dimensions());
+            if matches!(format, OutputFormat::Png | OutputFormat::WebPLossless) {
+                assert_eq!(decoded, group_pic);
+            }
+        }
+        assert_eq!(
+            "webp".parse::<OutputFormat>().unwrap(),
+            OutputFormat::WebP(90)
+        );
+    }
+
+    #[test]
+    fn parse_colors() {
+        assert_eq!(
+            parse_color("#5865f2").unwrap(),
+            Rgba([0x58, 0x65, 0xf2, 255])
+        );
+        assert_eq!(
+            parse_color("EB459E").unwrap(),
+            Rgba([0xeb, 0x45, 0x9e, 255])
+        );
+        assert_eq!(parse_color("#00000080").unwrap(), Rgba([0, 0, 0, 0x80]));
+        assert!(parse_color("#fff").is_err());
+        assert!(parse_color("+12345").is_err());
+        assert!(parse_color("#5865f2z").is_err());
+    }
+
+    #[test]
+    fn encode_within_budget() {
+        // noise hardly compresses
+        let mut rng = SplitMix64(0);
+        let noise = ImageBuffer::from_fn(640, 320, |_, _| {
+            Rgba([rng.next_u8(), rng.next_u8(), rng.next_u8(), 255])
+        });
+        let png = encode_group_pic_png(&noise).unwrap();
+        let (format, bytes) = encode_group_pic_within_budget(&noise, png.len()).unwrap();
+        assert_eq!((format, bytes), (OutputFormat::Png, png));
+        let (format, bytes) = encode_group_pic_within_budget(&noise, 50_000).unwrap();
+        assert!(matches!(format, OutputFormat::WebP(_)));
+        assert!(bytes.len() <= 50_000);
+        assert!(matches!(
+            encode_group_pic_within_budget(&noise, 10),
+            Err(GenPicError::OverBudget { max_bytes: 10, .. })
+        ));
+    }
+
+    #[test]
+    fn render_tile_sizes() {
+        let avatars = fixture_avatars(7, 128, 19);
+        for tile_size in TILE_SIZES {
+            let config = GroupPicConfig {
+                header_text: "niji3rd-live-day1".into(),
+                tile_size,
+                num_of_avatars_in_a_row: Some(5),
+                badges: Some(vec![
+                    VoiceBadges {
+                        self_deaf: true,
+                        server_mute: true,
+                        streaming: true,
+                        video: true,
+                        ..Default::default()
+                    };
+                    7
+                ]),
+                ..Default::default()
+            };
+            let group_pic = render_group_pic(&avatars, &config).unwrap();
+            assert_eq!(group_pic.dimensions(), (tile_size * 5, 64 + tile_size * 2));
+        }
+    }
+
+    #[test]
+    fn render_layouts() {
+        let avatars = fixture_avatars(12, 128, 20);
+        for layout in Layout::ALL {
+            let config = GroupPicConfig {
+                header_text: "niji3rd-live-day1".into(),
+                layout,
+                ..Default::default()
+            };
+            let group_pic = render_group_pic(&avatars, &config).unwrap();
+            let (w, h) = group_pic.dimensions();
+            match layout {
+                Layout::Rings => assert_eq!(w, h),
+                Layout::AspectRatioGrid => assert_eq!(w * 9 / 16, h),
+                _ => assert_eq!(w, 128 * 5),
+            }
+        }
+        assert_eq!("honeycomb".parse::<Layout>().unwrap(), Layout::Honeycomb);
+    }
+
+    #[test]
+    fn render_sections() {
+        let avatars = fixture_avatars(10, 128, 21);
+        let red = Rgba([237, 66, 69, 255]);
+        let config = GroupPicConfig {
+            header_text: "niji3rd-live-day1".into(),
+            sections: Some(vec![
+                AvatarSection {
+                    title: "Staff".into(),
+                    color: Some(red),
+                    count: 5,
+                },
+                AvatarSection {
+                    title: "Empty".into(),
+                    color: None,
+                    count: 0,
+                },
+                AvatarSection {
+                    title: "Audience".into(),
+                    color: None,
+                    count: 5,
+                },
+            ]),
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        let (w, h) = group_pic.dimensions();
+        assert_eq!(w, 128 * 5);
+        // the header, then a sub-header above each row of avatars
+        let title_h = (h - 64 - 2 * 128) / 2;
+        let has_color = |rows: Range<u32>, color: Rgba<u8>| {
+            rows.flat_map(|y| (0..w).map(move |x| (x, y)))
+                .any(|(x, y)| *group_pic.get_pixel(x, y) == color)
+        };
+        assert!(has_color(64..64 + title_h, red));
+        assert!(!has_color(64..64 + title_h, config.style.text_color));
+        let second = 64 + title_h + 128;
+        assert!(has_color(second..second + title_h, config.style.text_color));
+
+        let config = GroupPicConfig {
+            layout: Layout::Rings,
+            ..config
+        };
+        render_group_pic(&avatars, &config).unwrap();
+        let config = GroupPicConfig {
+            sections: Some(vec![AvatarSection {
+                title: "Staff".into(),
+                color: None,
+                count: 3,
+            }]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            render_group_pic(&avatars, &config),
+            Err(GenPicError::SectionCountMismatch {
+                avatars: 10,
+                sections: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn render_background_covers() {
+        let avatars = fixture_avatars(7, 128, 22);
+        let (red, blue) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]));
+        // twice as wide as tall, so that it is cropped on the sides
+        let image = ImageBuffer::from_fn(100, 50, |x, _| if x < 50 { red } else { blue });
+        let config = GroupPicConfig {
+            background: Some(Background {
+                image,
+                blur: 0.,
+                dim: 0.,
+            }),
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        let (w, h) = group_pic.dimensions();
+        // the masked out corner of the first avatar and the empty end of the last row
+        assert_eq!(*group_pic.get_pixel(0, 64), red);
+        assert_eq!(*group_pic.get_pixel(w - 1, h - 1), blue);
+
+        let mut background = config.background.clone().unwrap();
+        background.blur = 4.;
+        background.dim = 1.;
+        let config = GroupPicConfig {
+            background: Some(background),
+            ..config
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        assert_eq!(
+            *group_pic.get_pixel(w - 1, h - 1),
+            config.style.background_color
         );
     }
+
+    #[test]
+    fn render_rings() {
+        let black = Rgba([0, 0, 0, 255]);
+        let red = Rgba([237, 66, 69, 255]);
+        let avatars = vec![ImageBuffer::from_pixel(128, 128, black); 2];
+        let config = GroupPicConfig {
+            rings: Some(vec![red, red]),
+            ..Default::default()
+        };
+        let group_pic = render_group_pic(&avatars, &config).unwrap();
+        // the ring covers the top edge of the circle, but not the middle of the avatar
+        assert_eq!(*group_pic.get_pixel(64, 64 + 2), red);
+        assert_eq!(*group_pic.get_pixel(64, 64 + 8), black);
+        assert_eq!(*group_pic.get_pixel(128 + 64, 64 + 64), black);
+
+        let is_aliased = |ring: &GrayImage| ring.pixels().all(|p| p.0[0] == 0 || p.0[0] == 255);
+        for shape in MaskShape::ALL {
+            assert!(is_aliased(&render_ring(shape, 128, 4, 1)));
+            assert!(!is_aliased(&render_ring(shape, 128, 4, 4)));
+            assert!(render_ring(shape, 128, 0, 4).pixels().all(|p| p.0[0] == 0));
+        }
+
+        let config = GroupPicConfig {
+            rings: Some(vec![red]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            render_group_pic(&avatars, &config),
+            Err(GenPicError::RingCountMismatch {
+                avatars: 2,
+                rings: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn parallel_render_matches_sequential() {
+        // not the tile size, so that every avatar is resized
+        let avatars = fixture_avatars(30, 96, 23);
+        let config = GroupPicConfig {
+            header_text: "niji3rd-live-day1".into(),
+            captions: Some((0..30).map(|i| format!("Member {}", i)).collect()),
+            rings: Some(vec![Rgba([237, 66, 69, 255]); 30]),
+            ..Default::default()
+        };
+        let render_with_threads = |num_threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .unwrap()
+                .install(|| render_group_pic(&avatars, &config).unwrap())
+        };
+        let sequential = render_with_threads(1);
+        assert_eq!(sequential.as_raw(), render_with_threads(4).as_raw());
+    }
+
+    #[test]
+    fn render_errors() {
+        let config = GroupPicConfig::default();
+        assert!(matches!(
+            render_group_pic(&[], &config),
+            Err(GenPicError::NoAvatars)
+        ));
+        let config = GroupPicConfig {
+            num_of_avatars_in_a_row: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            render_group_pic(&fixture_avatars(1, 128, 24), &config),
+            Err(GenPicError::ZeroColumns)
+        ));
+        let config = GroupPicConfig {
+            captions: Some(vec!["Ayumu".into()]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            render_group_pic(&fixture_avatars(2, 128, 25), &config),
+            Err(GenPicError::CaptionCountMismatch {
+                avatars: 2,
+                captions: 1
+            })
+        ));
+        let config = GroupPicConfig {
+            tile_size: 100,
+            ..Default::default()
+        };
+        assert!(matches!(
+            render_group_pic(&fixture_avatars(1, 128, 26), &config),
+            Err(GenPicError::InvalidTileSize(100))
+        ));
+        let config = GroupPicConfig {
+            badges: Some(vec![VoiceBadges::default(); 3]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            render_group_pic(&fixture_avatars(2, 128, 27), &config),
+            Err(GenPicError::BadgeCountMismatch {
+                avatars: 2,
+                badges: 3
+            })
+        ));
+    }
 }
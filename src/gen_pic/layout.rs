@@ -0,0 +1,342 @@
+//! Placement of the avatar tiles in the group picture
+
+use std::{f32::consts::PI, str::FromStr};
+
+use num::{integer::Roots, Integer};
+
+use super::GenPicError;
+
+/// Arrangement of the avatars in the group picture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Layout {
+    /// Rows filled from left to right, with a ragged last row
+    #[default]
+    Grid,
+    /// Rows filled from left to right, with the last row centered
+    CenteredGrid,
+    /// Every other row shifted by half a tile and one tile shorter, packed like a honeycomb
+    Honeycomb,
+    /// Concentric rings of avatars around the header text
+    Rings,
+    /// Grid with the number of columns that comes closest to the aspect ratio, padded to it
+    AspectRatioGrid,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 5] = [
+        Layout::Grid,
+        Layout::CenteredGrid,
+        Layout::Honeycomb,
+        Layout::Rings,
+        Layout::AspectRatioGrid,
+    ];
+
+    /// Place `n` tiles, with `columns` tiles in a row for the layouts made of rows
+    ///
+    /// `header_h` is the height the header is expected to take above the tiles.
+    pub(super) fn place(
+        &self,
+        n: u32,
+        columns: Option<u32>,
+        tile: &TileMetrics,
+        aspect_ratio: (u32, u32),
+        header_h: u32,
+    ) -> Result<Placement, GenPicError> {
+        let columns = match columns {
+            Some(0) => return Err(GenPicError::ZeroColumns),
+            Some(n) => n,
+            None => core::cmp::max(n.sqrt(), 5),
+        };
+        let placement = match self {
+            Self::Grid => place_grid(n, columns, tile, false),
+            Self::CenteredGrid => place_grid(n, columns, tile, true),
+            Self::Honeycomb => place_honeycomb(n, columns, tile),
+            Self::Rings => place_rings(n, tile),
+            Self::AspectRatioGrid => {
+                let (rw, rh) = aspect_ratio;
+                if rw == 0 || rh == 0 {
+                    return Err(GenPicError::InvalidAspectRatio(rw, rh));
+                }
+                let target = (rw as f32 / rh as f32).ln();
+                let distance =
+                    |p: &Placement| ((p.w as f32 / (p.h + header_h) as f32).ln() - target).abs();
+                (1..=n)
+                    .map(|columns| place_grid(n, columns, tile, true))
+                    .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+                    .unwrap_or_else(|| place_grid(n, 1, tile, true))
+            }
+        };
+        Ok(placement)
+    }
+}
+
+impl AsRef<str> for Layout {
+    fn as_ref(&self) -> &'static str {
+        match self {
+            Self::Grid => "grid",
+            Self::CenteredGrid => "centered-grid",
+            Self::Honeycomb => "honeycomb",
+            Self::Rings => "rings",
+            Self::AspectRatioGrid => "aspect-ratio",
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = GenPicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|layout| layout.as_ref() == s)
+            .copied()
+            .ok_or_else(|| GenPicError::UnknownLayout(s.to_owned()))
+    }
+}
+
+/// Sizes of a tile and the space around it in pixels
+pub(super) struct TileMetrics {
+    /// Width and height of the avatar
+    pub size: u32,
+    /// Height of the avatar together with its caption
+    pub h: u32,
+    pub gap: u32,
+    pub padding: u32,
+    /// Whether the avatar fits in a circle of its size
+    pub round: bool,
+}
+
+/// Where the header goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum HeaderPlacement {
+    /// Across the top, above the tiles
+    Top,
+    /// In a disc of `radius` in the middle of the tiles
+    Center { radius: u32 },
+}
+
+/// Top left corner of every tile, and the size of the area they take
+///
+/// For `HeaderPlacement::Top` the area starts below the header.
+pub(super) struct Placement {
+    pub positions: Vec<(u32, u32)>,
+    pub w: u32,
+    pub h: u32,
+    pub header: HeaderPlacement,
+}
+
+impl Placement {
+    /// Pad the area on both sides to match `aspect_ratio` exactly, with the header on top
+    pub(super) fn pad_to_aspect_ratio(&mut self, header_h: u32, (rw, rh): (u32, u32)) {
+        let total_h = (header_h + self.h) as u64;
+        let (rw, rh) = (rw as u64, rh as u64);
+        let (dx, dy) = if (self.w as u64) * rh < total_h * rw {
+            let w = Integer::div_ceil(&(total_h * rw), &rh) as u32;
+            let dx = (w - self.w) / 2;
+            self.w = w;
+            (dx, 0)
+        } else {
+            let total_h = Integer::div_ceil(&(self.w as u64 * rh), &rw) as u32;
+            let h = total_h.saturating_sub(header_h);
+            let dy = h.saturating_sub(self.h) / 2;
+            self.h = self.h.max(h);
+            (0, dy)
+        };
+        for (x, y) in &mut self.positions {
+            *x += dx;
+            *y += dy;
+        }
+    }
+}
+
+/// Rows of `columns` tiles below the header, with the padding on every side but the top
+fn place_grid(n: u32, columns: u32, tile: &TileMetrics, center_last_row: bool) -> Placement {
+    let rows = Integer::div_ceil(&n, &columns);
+    let last_row_len = n - (rows - 1) * columns;
+    let positions = (0..n)
+        .map(|i| {
+            let (row, col) = (i / columns, i % columns);
+            let mut x = tile.padding + col * (tile.size + tile.gap);
+            if center_last_row && row == rows - 1 {
+                x += (columns - last_row_len) * (tile.size + tile.gap) / 2;
+            }
+            (x, row * (tile.h + tile.gap))
+        })
+        .collect();
+    Placement {
+        positions,
+        w: 2 * tile.padding + columns * tile.size + (columns - 1) * tile.gap,
+        h: rows * tile.h + (rows - 1) * tile.gap + tile.padding,
+        header: HeaderPlacement::Top,
+    }
+}
+
+/// Rows of `columns` and `columns - 1` tiles in turn, where the shorter rows are shifted by half
+/// a tile and nestle into the row above unless the avatars are not round or there are captions
+/// in between
+fn place_honeycomb(n: u32, columns: u32, tile: &TileMetrics) -> Placement {
+    if columns < 2 {
+        return place_grid(n, columns, tile, false);
+    }
+    let pitch = tile.size + tile.gap;
+    let row_step = if tile.round && tile.h == tile.size {
+        (pitch as f32 * 3f32.sqrt() / 2.).round() as u32
+    } else {
+        tile.h + tile.gap
+    };
+    let mut positions = Vec::with_capacity(n as usize);
+    let mut row = 0;
+    while (positions.len() as u32) < n {
+        let (row_len, x_offset) = if row % 2 == 0 {
+            (columns, 0)
+        } else {
+            (columns - 1, pitch / 2)
+        };
+        let remaining = n - positions.len() as u32;
+        positions.extend(
+            (0..row_len.min(remaining))
+                .map(|col| (tile.padding + x_offset + col * pitch, row * row_step)),
+        );
+        row += 1;
+    }
+    Placement {
+        positions,
+        w: 2 * tile.padding + columns * tile.size + (columns - 1) * tile.gap,
+        h: (row - 1) * row_step + tile.h + tile.padding,
+        header: HeaderPlacement::Top,
+    }
+}
+
+/// Rings around a disc of the size of a tile for the header, each filled before the next
+fn place_rings(n: u32, tile: &TileMetrics) -> Placement {
+    let size = tile.size as f32;
+    let pitch = (tile.size + tile.gap) as f32;
+    let inner_radius = size;
+
+    // the radius of each ring and the number of tiles on it, where neighbouring tiles on a ring
+    // are a pitch apart in a straight line
+    let mut rings = vec![];
+    let mut radius = inner_radius + size / 2. + tile.gap as f32;
+    let mut remaining = n;
+    while remaining > 0 {
+        let capacity = (PI / (pitch / (2. * radius)).min(1.).asin()).floor() as u32;
+        let count = capacity.max(1).min(remaining);
+        rings.push((radius, count));
+        remaining -= count;
+        radius += (tile.h + tile.gap) as f32;
+    }
+
+    let outer_radius = rings.last().map_or(inner_radius, |&(radius, _)| radius);
+    let extent = (outer_radius + size / 2. + (tile.h - tile.size) as f32).ceil() as u32;
+    let center = (tile.padding + extent) as f32;
+    let positions = rings
+        .iter()
+        .flat_map(|&(radius, count)| {
+            (0..count).map(move |i| {
+                // clockwise from the top
+                let angle = -PI / 2. + 2. * PI * i as f32 / count as f32;
+                let x = center + radius * angle.cos() - size / 2.;
+                let y = center + radius * angle.sin() - size / 2.;
+                (x.round().max(0.) as u32, y.round().max(0.) as u32)
+            })
+        })
+        .collect();
+    let side = 2 * (tile.padding + extent);
+    Placement {
+        positions,
+        w: side,
+        h: side,
+        header: HeaderPlacement::Center {
+            radius: inner_radius as u32,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TILE: TileMetrics = TileMetrics {
+        size: 128,
+        h: 128,
+        gap: 8,
+        padding: 8,
+        round: true,
+    };
+
+    fn assert_no_overlap(placement: &Placement) {
+        for (i, &(x0, y0)) in placement.positions.iter().enumerate() {
+            assert!(x0 + TILE.size <= placement.w && y0 + TILE.h <= placement.h);
+            for &(x1, y1) in &placement.positions[i + 1..] {
+                let (dx, dy) = (x0 as f32 - x1 as f32, y0 as f32 - y1 as f32);
+                assert!(dx.hypot(dy) >= TILE.size as f32 - 1.);
+            }
+        }
+    }
+
+    #[test]
+    fn layouts_do_not_overlap() {
+        for layout in Layout::ALL {
+            for n in [1, 2, 7, 25, 99] {
+                let placement = layout.place(n, None, &TILE, (16, 9), 64).unwrap();
+                assert_eq!(placement.positions.len(), n as usize);
+                assert_no_overlap(&placement);
+            }
+        }
+    }
+
+    #[test]
+    fn center_last_row() {
+        let placement = Layout::CenteredGrid
+            .place(7, Some(5), &TILE, (16, 9), 64)
+            .unwrap();
+        assert_eq!(placement.positions[5].0, 8 + 136 * 3 / 2);
+        assert_eq!(
+            placement.w - placement.positions[6].0 - 128,
+            placement.positions[5].0
+        );
+    }
+
+    #[test]
+    fn honeycomb_rows_alternate() {
+        let placement = Layout::Honeycomb
+            .place(12, Some(5), &TILE, (16, 9), 64)
+            .unwrap();
+        let rows: Vec<_> = placement.positions.iter().map(|&(_, y)| y).collect();
+        assert_eq!(
+            rows,
+            [[0; 5].to_vec(), [118; 4].to_vec(), [236; 3].to_vec()].concat()
+        );
+        assert_eq!(placement.positions[5].0, 8 + 136 / 2);
+
+        let square = TileMetrics {
+            round: false,
+            ..TILE
+        };
+        let placement = Layout::Honeycomb
+            .place(12, Some(5), &square, (16, 9), 64)
+            .unwrap();
+        let rows: Vec<_> = placement.positions.iter().map(|&(_, y)| y).collect();
+        assert_eq!(
+            rows,
+            [[0; 5].to_vec(), [136; 4].to_vec(), [272; 3].to_vec()].concat()
+        );
+    }
+
+    #[test]
+    fn pad_to_aspect_ratio() {
+        for n in [1, 7, 25, 99] {
+            let mut placement = Layout::AspectRatioGrid
+                .place(n, None, &TILE, (16, 9), 64)
+                .unwrap();
+            placement.pad_to_aspect_ratio(64, (16, 9));
+            let ratio = placement.w as f32 / (placement.h + 64) as f32;
+            assert!((ratio - 16. / 9.).abs() < 0.01, "{} avatars: {}", n, ratio);
+            assert_no_overlap(&placement);
+        }
+        assert!(matches!(
+            Layout::AspectRatioGrid.place(1, None, &TILE, (0, 9), 64),
+            Err(GenPicError::InvalidAspectRatio(0, 9))
+        ));
+    }
+}
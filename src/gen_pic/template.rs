@@ -0,0 +1,554 @@
+//! Picture templates describing the look of a group picture, loaded from TOML or JSON
+//!
+//! Every field of a template is optional and falls back to the default of `GroupPicConfig`, so a
+//! template only needs to list what it changes:
+//!
+//! ```toml
+//! [canvas]
+//! theme = "discord-light"
+//! background = "sakura.png"
+//!
+//! [header]
+//! text = "🌸 {title} 🌸"
+//!
+//! [tiles]
+//! shape = "heart"
+//! layout = "honeycomb"
+//!
+//! [colors]
+//! text = "#5c2a3e"
+//! ring = "#f4a7c0"
+//!
+//! [effects]
+//! preset = "stroke"
+//!
+//! [[overlays]]
+//! image = "petals.png"
+//! anchor = "top-right"
+//!
+//! [footer]
+//! text = "Spring Live 2022"
+//! ```
+//!
+//! Images are looked up relative to the template file, and may not lie outside its directory.
+
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use image::Rgba;
+use serde::Deserialize;
+
+use super::{
+    parse_color, Background, GenPicError, GroupPicConfig, Layout, MaskShape, Overlay,
+    OverlayAnchor, Shadow, Stroke, TextEffect, Theme, TILE_SIZES,
+};
+
+/// Look of a group picture
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Template {
+    pub canvas: CanvasTemplate,
+    pub header: HeaderTemplate,
+    pub tiles: TilesTemplate,
+    pub colors: ColorsTemplate,
+    pub fonts: FontsTemplate,
+    pub effects: EffectsTemplate,
+    /// Images drawn over the group picture in order
+    pub overlays: Vec<OverlayTemplate>,
+    pub footer: Option<FooterTemplate>,
+    /// Directory the paths of images are relative to
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+/// Theme, spacing and background of the whole picture
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CanvasTemplate {
+    /// Name of the built-in theme the colors and spacing start from
+    pub theme: Option<String>,
+    pub padding: Option<u32>,
+    pub tile_gap: Option<u32>,
+    pub transparent: Option<bool>,
+    /// Image behind the avatars
+    pub background: Option<PathBuf>,
+    pub background_blur: Option<f32>,
+    pub background_dim: Option<f32>,
+    /// Width and height of the aspect ratio layout
+    pub aspect_ratio: Option<(u32, u32)>,
+}
+
+/// Text and size of the header
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HeaderTemplate {
+    /// Header text, where `{title}` stands for the name of the voice channel
+    pub text: Option<String>,
+    /// Subtitle, where `{title}` stands for the name of the voice channel
+    pub subtitle: Option<String>,
+    pub height: Option<u32>,
+    pub max_lines: Option<u32>,
+}
+
+/// Size, shape and arrangement of the avatars
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TilesTemplate {
+    pub size: Option<u32>,
+    /// Name of the mask shape
+    pub shape: Option<String>,
+    /// Name of the layout
+    pub layout: Option<String>,
+    pub columns: Option<u32>,
+    pub ring_thickness: Option<u32>,
+}
+
+/// Colors overriding those of the theme, as `#rrggbb` or `#rrggbbaa`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ColorsTemplate {
+    pub background: Option<String>,
+    pub header: Option<String>,
+    pub text: Option<String>,
+    /// Color of a ring around every avatar
+    pub ring: Option<String>,
+}
+
+/// Font sizes in pixels
+///
+/// The typefaces are the fonts embedded into the binary.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FontsTemplate {
+    pub header_size: Option<f32>,
+    pub header_min_size: Option<f32>,
+    pub subtitle_size: Option<f32>,
+    pub caption_size: Option<f32>,
+    pub section_size: Option<f32>,
+    pub footer_size: Option<f32>,
+}
+
+/// Text effects, starting from a preset and refined by the other fields
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct EffectsTemplate {
+    /// Name of the text effect turned on in colors contrasting with the text
+    pub preset: Option<String>,
+    pub stroke: Option<StrokeTemplate>,
+    pub shadow: Option<ShadowTemplate>,
+    /// Color of the bar behind the header text
+    pub bar: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrokeTemplate {
+    pub width: f32,
+    pub color: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShadowTemplate {
+    pub offset: (f32, f32),
+    #[serde(default)]
+    pub blur: f32,
+    pub color: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverlayTemplate {
+    pub image: PathBuf,
+    /// Name of the overlay anchor, the center if `None`
+    pub anchor: Option<String>,
+    #[serde(default)]
+    pub margin: u32,
+    pub opacity: Option<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FooterTemplate {
+    /// Footer text, where `{title}` stands for the name of the voice channel
+    pub text: String,
+}
+
+impl Template {
+    /// Parse a template from TOML
+    pub fn from_toml(s: &str) -> Result<Self, GenPicError> {
+        toml::from_str(s).map_err(|e| GenPicError::InvalidTemplate(e.to_string()))
+    }
+
+    /// Parse a template from JSON
+    pub fn from_json(s: &str) -> Result<Self, GenPicError> {
+        serde_json::from_str(s).map_err(|e| GenPicError::InvalidTemplate(e.to_string()))
+    }
+
+    /// Read a template from a `.toml` or `.json` file, with images relative to the file
+    pub fn load(path: &Path) -> Result<Self, GenPicError> {
+        let s = fs::read_to_string(path)?;
+        let mut template = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&s),
+            Some("json") => Self::from_json(&s),
+            _ => Err(GenPicError::InvalidTemplate(format!(
+                "{} is neither .toml nor .json",
+                path.display()
+            ))),
+        }
+        .map_err(|e| match e {
+            GenPicError::InvalidTemplate(msg) => {
+                GenPicError::InvalidTemplate(format!("{}: {}", path.display(), msg))
+            }
+            e => e,
+        })?;
+        template.dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(template)
+    }
+
+    /// Configuration of a group picture of `avatars` avatars titled `title`
+    ///
+    /// Images are read from disk, so this is best called on a blocking thread.
+    pub fn config(&self, title: &str, avatars: usize) -> Result<GroupPicConfig, GenPicError> {
+        self.validate()?;
+        let mut config = GroupPicConfig {
+            header_text: title.to_owned(),
+            ..Default::default()
+        };
+        let fill = |text: &str| text.replace("{title}", title);
+
+        let canvas = &self.canvas;
+        if let Some(theme) = &canvas.theme {
+            config.style = theme.parse::<Theme>()?.style();
+        }
+        set(&mut config.style.padding, canvas.padding);
+        set(&mut config.style.tile_gap, canvas.tile_gap);
+        set(&mut config.transparent, canvas.transparent);
+        set(&mut config.aspect_ratio, canvas.aspect_ratio);
+        if let Some(path) = &canvas.background {
+            let mut background = Background::new(self.open_image(path)?);
+            set(&mut background.blur, canvas.background_blur);
+            set(&mut background.dim, canvas.background_dim);
+            config.background = Some(background);
+        }
+
+        let header = &self.header;
+        if let Some(text) = &header.text {
+            config.header_text = fill(text);
+        }
+        config.subtitle = header.subtitle.as_deref().map(fill);
+        set(&mut config.header_h, header.height);
+        set(&mut config.header_max_lines, header.max_lines);
+
+        let tiles = &self.tiles;
+        set(&mut config.tile_size, tiles.size);
+        if let Some(shape) = &tiles.shape {
+            config.mask_shape = shape.parse::<MaskShape>()?;
+        }
+        if let Some(layout) = &tiles.layout {
+            config.layout = layout.parse::<Layout>()?;
+        }
+        config.num_of_avatars_in_a_row = tiles.columns;
+        set(&mut config.ring_thickness, tiles.ring_thickness);
+
+        let colors = &self.colors;
+        set(
+            &mut config.style.background_color,
+            parse_opt(&colors.background)?,
+        );
+        set(&mut config.style.header_color, parse_opt(&colors.header)?);
+        set(&mut config.style.text_color, parse_opt(&colors.text)?);
+        config.rings = parse_opt(&colors.ring)?.map(|color| vec![color; avatars]);
+
+        let fonts = &self.fonts;
+        set(&mut config.header_font_size, fonts.header_size);
+        set(&mut config.header_min_font_size, fonts.header_min_size);
+        set(&mut config.subtitle_font_size, fonts.subtitle_size);
+        set(&mut config.caption_font_size, fonts.caption_size);
+        set(&mut config.section_font_size, fonts.section_size);
+        set(&mut config.footer_font_size, fonts.footer_size);
+
+        let effects = &self.effects;
+        if let Some(preset) = &effects.preset {
+            config.style = config.style.with_text_effect(preset.parse::<TextEffect>()?);
+        }
+        if let Some(stroke) = &effects.stroke {
+            config.style.text_stroke = Some(Stroke {
+                width: stroke.width,
+                color: parse_color(&stroke.color)?,
+            });
+        }
+        if let Some(shadow) = &effects.shadow {
+            config.style.text_shadow = Some(Shadow {
+                offset: shadow.offset,
+                blur: shadow.blur,
+                color: parse_color(&shadow.color)?,
+            });
+        }
+        if let Some(bar) = &effects.bar {
+            config.style.header_bar = Some(parse_color(bar)?);
+        }
+
+        config.overlays = self
+            .overlays
+            .iter()
+            .map(|overlay| {
+                let mut o = Overlay::new(self.open_image(&overlay.image)?);
+                if let Some(anchor) = &overlay.anchor {
+                    o.anchor = anchor.parse::<OverlayAnchor>()?;
+                }
+                o.margin = overlay.margin;
+                set(&mut o.opacity, overlay.opacity);
+                Ok(o)
+            })
+            .collect::<Result<_, GenPicError>>()?;
+        config.footer = self.footer.as_ref().map(|footer| fill(&footer.text));
+        Ok(config)
+    }
+
+    /// Check the values which parse but cannot be rendered
+    fn validate(&self) -> Result<(), GenPicError> {
+        if let Some(size) = self.tiles.size {
+            if !TILE_SIZES.contains(&size) {
+                return Err(GenPicError::InvalidTileSize(size));
+            }
+        }
+        if self.tiles.columns == Some(0) {
+            return Err(GenPicError::ZeroColumns);
+        }
+        if let Some((w, h)) = self.canvas.aspect_ratio {
+            if w == 0 || h == 0 {
+                return Err(GenPicError::InvalidAspectRatio(w, h));
+            }
+        }
+        let fonts = &self.fonts;
+        let font_sizes = [
+            fonts.header_size,
+            fonts.header_min_size,
+            fonts.subtitle_size,
+            fonts.caption_size,
+            fonts.section_size,
+            fonts.footer_size,
+        ];
+        if let Some(size) = font_sizes
+            .iter()
+            .flatten()
+            .find(|&&s| !(s.is_finite() && s > 0.))
+        {
+            return Err(GenPicError::InvalidTemplate(format!(
+                "font size cannot be {}",
+                size
+            )));
+        }
+        let widths = [
+            self.canvas.background_blur,
+            self.effects.stroke.as_ref().map(|stroke| stroke.width),
+            self.effects.shadow.as_ref().map(|shadow| shadow.blur),
+        ];
+        if let Some(width) = widths
+            .iter()
+            .flatten()
+            .find(|&&w| !(w.is_finite() && w >= 0.))
+        {
+            return Err(GenPicError::InvalidTemplate(format!(
+                "blur or stroke width cannot be {}",
+                width
+            )));
+        }
+        Ok(())
+    }
+
+    /// Read the image at `path` relative to the template
+    ///
+    /// Paths may only lead into the directory of the template, so that a template cannot read
+    /// any other file the bot has access to.
+    fn open_image(&self, path: &Path) -> Result<image::RgbaImage, GenPicError> {
+        if !path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(GenPicError::InvalidTemplate(format!(
+                "{} is outside the directory of the template",
+                path.display()
+            )));
+        }
+        Ok(image::open(self.dir.join(path))?.into_rgba8())
+    }
+}
+
+/// Overwrite `field` with `value` if there is one
+fn set<T>(field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *field = value;
+    }
+}
+
+fn parse_opt(s: &Option<String>) -> Result<Option<Rgba<u8>>, GenPicError> {
+    s.as_deref().map(parse_color).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gen_pic::GroupPicStyle;
+
+    #[test]
+    fn empty_template_is_the_default() {
+        let config = Template::from_toml("")
+            .unwrap()
+            .config("niji3rd", 3)
+            .unwrap();
+        let default = GroupPicConfig::default();
+        assert_eq!(config.header_text, "niji3rd");
+        assert_eq!(config.style, default.style);
+        assert_eq!(config.tile_size, default.tile_size);
+        assert_eq!(config.layout, default.layout);
+        assert_eq!(config.rings, None);
+        assert_eq!(config.footer, None);
+        assert!(config.overlays.is_empty());
+    }
+
+    #[test]
+    fn toml_and_json_agree() {
+        let toml = r##"
+            [canvas]
+            theme = "discord-light"
+            tile-gap = 4
+            aspect-ratio = [4, 3]
+
+            [header]
+            text = "🌸 {title} 🌸"
+            max-lines = 2
+
+            [tiles]
+            size = 64
+            shape = "heart"
+            layout = "honeycomb"
+            columns = 6
+
+            [colors]
+            text = "#5c2a3e"
+            ring = "#f4a7c0"
+
+            [fonts]
+            caption-size = 14
+
+            [effects]
+            preset = "stroke"
+            bar = "#ffffff80"
+
+            [footer]
+            text = "{title} · Spring Live"
+        "##;
+        let json = r##"{
+            "canvas": {"theme": "discord-light", "tile-gap": 4, "aspect-ratio": [4, 3]},
+            "header": {"text": "🌸 {title} 🌸", "max-lines": 2},
+            "tiles": {"size": 64, "shape": "heart", "layout": "honeycomb", "columns": 6},
+            "colors": {"text": "#5c2a3e", "ring": "#f4a7c0"},
+            "fonts": {"caption-size": 14},
+            "effects": {"preset": "stroke", "bar": "#ffffff80"},
+            "footer": {"text": "{title} · Spring Live"}
+        }"##;
+        let template = Template::from_toml(toml).unwrap();
+        assert_eq!(template, Template::from_json(json).unwrap());
+
+        let config = template.config("niji3rd", 2).unwrap();
+        assert_eq!(config.header_text, "🌸 niji3rd 🌸");
+        assert_eq!(config.header_max_lines, 2);
+        assert_eq!(config.aspect_ratio, (4, 3));
+        assert_eq!(config.tile_size, 64);
+        assert_eq!(config.mask_shape, MaskShape::Heart);
+        assert_eq!(config.layout, Layout::Honeycomb);
+        assert_eq!(config.num_of_avatars_in_a_row, Some(6));
+        assert_eq!(config.caption_font_size, 14.);
+        assert_eq!(config.rings, Some(vec![Rgba([0xf4, 0xa7, 0xc0, 255]); 2]));
+        assert_eq!(config.footer.as_deref(), Some("niji3rd · Spring Live"));
+
+        let light = GroupPicStyle::DISCORD_LIGHT;
+        assert_eq!(config.style.background_color, light.background_color);
+        assert_eq!(config.style.tile_gap, 4);
+        assert_eq!(config.style.text_color, Rgba([0x5c, 0x2a, 0x3e, 255]));
+        assert!(config.style.text_stroke.is_some());
+        assert_eq!(config.style.header_bar, Some(Rgba([255, 255, 255, 0x80])));
+    }
+
+    #[test]
+    fn load_template_with_images() {
+        let dir = std::env::temp_dir().join(format!("groupic-template-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let red = image::RgbaImage::from_pixel(16, 16, Rgba([255, 0, 0, 255]));
+        red.save(dir.join("petal.png")).unwrap();
+        let path = dir.join("spring.toml");
+        fs::write(
+            &path,
+            "[canvas]\nbackground = \"petal.png\"\n\n\
+             [[overlays]]\nimage = \"petal.png\"\nanchor = \"bottom-right\"\nmargin = 4\n",
+        )
+        .unwrap();
+        let config = Template::load(&path).unwrap().config("", 1);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.background.unwrap().image, red);
+        assert_eq!(config.overlays.len(), 1);
+        assert_eq!(config.overlays[0].anchor, OverlayAnchor::BottomRight);
+        assert_eq!(config.overlays[0].margin, 4);
+        assert!(matches!(
+            Template::load(&dir.join("spring.yaml")),
+            Err(GenPicError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_templates() {
+        let error = |toml: &str| Template::from_toml(toml).and_then(|t| t.config("", 1));
+        assert!(matches!(
+            error("[canvas]\ncolour = \"#000000\""),
+            Err(GenPicError::InvalidTemplate(_))
+        ));
+        assert!(matches!(
+            error("[tiles]\nsize = \"large\""),
+            Err(GenPicError::InvalidTemplate(_))
+        ));
+        assert!(matches!(
+            error("[tiles]\nshape = \"star\""),
+            Err(GenPicError::UnknownMaskShape(_))
+        ));
+        assert!(matches!(
+            error("[colors]\ntext = \"pink\""),
+            Err(GenPicError::InvalidColor(_))
+        ));
+        assert!(matches!(
+            error("[[overlays]]\nimage = \"missing.png\""),
+            Err(GenPicError::Image(_))
+        ));
+        for path in ["../petal.png", "/etc/petal.png", "petals/../../petal.png"] {
+            assert!(matches!(
+                error(&format!("[canvas]\nbackground = \"{}\"", path)),
+                Err(GenPicError::InvalidTemplate(_))
+            ));
+        }
+        assert!(matches!(
+            error("[tiles]\nsize = 100"),
+            Err(GenPicError::InvalidTileSize(100))
+        ));
+        assert!(matches!(
+            error("[tiles]\ncolumns = 0"),
+            Err(GenPicError::ZeroColumns)
+        ));
+        assert!(matches!(
+            error("[canvas]\naspect-ratio = [0, 9]"),
+            Err(GenPicError::InvalidAspectRatio(0, 9))
+        ));
+        assert!(matches!(
+            error("[fonts]\nheader-size = 0"),
+            Err(GenPicError::InvalidTemplate(_))
+        ));
+        assert!(matches!(
+            error("[effects.shadow]\noffset = [2, 2]\nblur = -1\ncolor = \"#000000\""),
+            Err(GenPicError::InvalidTemplate(_))
+        ));
+    }
+}
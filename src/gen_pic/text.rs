@@ -0,0 +1,827 @@
+//! Shaping, measuring and drawing of the text in the group picture
+
+use std::ops::Range;
+
+use glyph_brush_layout::{
+    ab_glyph::{
+        point, Font, FontRef, Glyph, GlyphId, GlyphImageFormat, InvalidFont, PxScale, ScaleFont,
+    },
+    FontId, SectionGlyph,
+};
+use image::{imageops::blur, GrayImage, ImageFormat, Luma, Rgba, RgbaImage};
+use lazy_static::lazy_static;
+use rustybuzz::{Direction, Face, UnicodeBuffer};
+use unicode_bidi::BidiInfo;
+
+use super::{blend_over, resize_premultiplied, GenPicError};
+
+const FONT_DATA: &[u8] = include_bytes!("../../NotoSansJP-Medium.otf");
+const LATIN_FONT_DATA: &[u8] = include_bytes!("../../NotoSansDisplay-SemiBold.ttf");
+const HANGUL_FONT_DATA: &[u8] = include_bytes!("../../NotoSansKR-Medium.otf");
+const ARABIC_FONT_DATA: &[u8] = include_bytes!("../../NotoSansArabic-Medium.ttf");
+const HEBREW_FONT_DATA: &[u8] = include_bytes!("../../NotoSansHebrew-Medium.ttf");
+const DEVANAGARI_FONT_DATA: &[u8] = include_bytes!("../../NotoSansDevanagari-Medium.ttf");
+const THAI_FONT_DATA: &[u8] = include_bytes!("../../NotoSansThai-Medium.ttf");
+const EMOJI_FONT_DATA: &[u8] = include_bytes!("../../NotoColorEmoji.ttf");
+const SYMBOLS_FONT_DATA: &[u8] = include_bytes!("../../NotoSansSymbols2-Regular.ttf");
+/// Fonts to try in order for each grapheme of the text, indexed by `FontId`
+const FONT_FALLBACK_CHAIN: [&[u8]; 9] = [
+    FONT_DATA,
+    LATIN_FONT_DATA,
+    HANGUL_FONT_DATA,
+    ARABIC_FONT_DATA,
+    HEBREW_FONT_DATA,
+    DEVANAGARI_FONT_DATA,
+    THAI_FONT_DATA,
+    EMOJI_FONT_DATA,
+    SYMBOLS_FONT_DATA,
+];
+/// Index of `EMOJI_FONT_DATA` in `FONT_FALLBACK_CHAIN`
+const EMOJI_FONT_ID: FontId = FontId(7);
+
+lazy_static! {
+    /// The fonts are parsed once and shared by every render
+    static ref FONTS: Result<Fonts, InvalidFont> = Fonts::load();
+}
+
+/// Horizontal alignment of a line of text to its anchor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Align {
+    /// The anchor is at the left edge of the text
+    Left,
+    /// The anchor is at the horizontal center of the text
+    Center,
+    /// The anchor is at the right edge of the text
+    Right,
+}
+
+impl Align {
+    /// Left edge of text `width` pixels wide aligned to `x`
+    fn left(self, x: f32, width: f32) -> f32 {
+        match self {
+            Align::Left => x,
+            Align::Center => x - width / 2.,
+            Align::Right => x - width,
+        }
+    }
+}
+
+/// Outline around the glyphs of a line of text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stroke {
+    /// Width of the outline outside of the glyphs in pixels
+    pub width: f32,
+    pub color: Rgba<u8>,
+}
+
+/// Blurred copy of a line of text drawn under it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shadow {
+    /// Offset from the text in pixels, positive to the right and down
+    pub offset: (f32, f32),
+    /// Standard deviation of the Gaussian blur in pixels, or 0 for a hard shadow
+    pub blur: f32,
+    pub color: Rgba<u8>,
+}
+
+/// Font size, color and effects of a line of text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextStyle {
+    /// Font size in pixels
+    pub font_size: f32,
+    pub color: Rgba<u8>,
+    /// Outline drawn around the glyphs, under them
+    pub stroke: Option<Stroke>,
+    /// Shadow drawn under the glyphs and the outline
+    pub shadow: Option<Shadow>,
+}
+
+impl TextStyle {
+    /// Plain text without effects
+    pub fn new(font_size: f32, color: Rgba<u8>) -> Self {
+        Self {
+            font_size,
+            color,
+            stroke: None,
+            shadow: None,
+        }
+    }
+}
+
+/// Size of a line of text in pixels, relative to the top of the line at its left edge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextBounds {
+    /// Sum of the advances of all glyphs, including kerning
+    pub width: f32,
+    /// Highest ascent of the fonts used, which is where the baseline is below the top
+    pub ascent: f32,
+    /// Lowest descent of the fonts used, negative below the baseline
+    pub descent: f32,
+}
+
+impl TextBounds {
+    /// Height of the line from the highest ascent to the lowest descent
+    pub fn height(&self) -> f32 {
+        self.ascent - self.descent
+    }
+}
+
+/// Rectangle in pixels outside of which nothing is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Clip {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl Clip {
+    /// The whole of `image`
+    pub fn image(image: &RgbaImage) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            w: image.width(),
+            h: image.height(),
+        }
+    }
+
+    /// Rows `y` to `y + h` of `image`, across its whole width
+    pub fn rows(image: &RgbaImage, y: u32, h: u32) -> Self {
+        Self {
+            x: 0,
+            y,
+            w: image.width(),
+            h,
+        }
+    }
+
+    fn contains(&self, x: i64, y: i64) -> bool {
+        x >= self.x as i64
+            && y >= self.y as i64
+            && x < self.x as i64 + self.w as i64
+            && y < self.y as i64 + self.h as i64
+    }
+}
+
+/// Measure `text` as a single line
+///
+/// The text goes through the same font fallback, bidi reordering and shaping as `draw`, so the
+/// bounds are exactly those of the drawn line.
+pub fn measure(text: &str, style: &TextStyle) -> Result<TextBounds, GenPicError> {
+    Ok(Fonts::get()?.shape(text, style.font_size).bounds())
+}
+
+/// Draw `text` as a single line with the top of the line at `anchor`, aligned horizontally to it
+///
+/// Glyphs outside of `image` are clipped. Returns the bounds of the line.
+pub fn draw(
+    image: &mut RgbaImage,
+    text: &str,
+    style: &TextStyle,
+    anchor: (f32, f32),
+    align: Align,
+) -> Result<TextBounds, GenPicError> {
+    let clip = Clip::image(image);
+    draw_clipped(image, text, style, anchor, align, clip)
+}
+
+/// Draw `text` like `draw`, but clipped to `clip`
+pub fn draw_clipped(
+    image: &mut RgbaImage,
+    text: &str,
+    style: &TextStyle,
+    anchor: (f32, f32),
+    align: Align,
+    clip: Clip,
+) -> Result<TextBounds, GenPicError> {
+    let fonts = Fonts::get()?;
+    let line = fonts.shape(text, style.font_size);
+    fonts.draw_line(image, &line, anchor, align, clip, style)?;
+    Ok(line.bounds())
+}
+
+/// A single line of shaped text
+pub(super) struct ShapedText {
+    /// Glyphs in visual order, positioned from x = 0 with the baseline at y = `ascent`
+    pub(super) glyphs: Vec<SectionGlyph>,
+    /// Font size the glyphs are scaled to in pixels
+    pub(super) font_size: f32,
+    /// Sum of the advances of all glyphs
+    pub(super) width: f32,
+    /// Highest ascent of the fonts used
+    pub(super) ascent: f32,
+    /// Lowest descent of the fonts used
+    pub(super) descent: f32,
+}
+
+impl ShapedText {
+    /// Height of the line from the highest ascent to the lowest descent
+    pub(super) fn height(&self) -> f32 {
+        self.ascent - self.descent
+    }
+
+    pub(super) fn bounds(&self) -> TextBounds {
+        TextBounds {
+            width: self.width,
+            ascent: self.ascent,
+            descent: self.descent,
+        }
+    }
+}
+
+/// Every font of `FONT_FALLBACK_CHAIN`, for rasterizing and for shaping
+pub(super) struct Fonts {
+    fonts: Vec<FontRef<'static>>,
+    faces: Vec<Face<'static>>,
+}
+
+impl Fonts {
+    /// Load every font of `FONT_FALLBACK_CHAIN`
+    fn load() -> Result<Self, InvalidFont> {
+        let fonts = FONT_FALLBACK_CHAIN
+            .iter()
+            .map(|data| FontRef::try_from_slice(data))
+            .collect::<Result<_, _>>()?;
+        let faces = FONT_FALLBACK_CHAIN
+            .iter()
+            .map(|data| Face::from_slice(data, 0).ok_or(InvalidFont))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { fonts, faces })
+    }
+
+    /// The fonts shared by every render
+    pub(super) fn get() -> Result<&'static Self, GenPicError> {
+        FONTS.as_ref().map_err(|&e| GenPicError::InvalidFont(e))
+    }
+
+    /// Pick the first font which has a glyph for every character of `grapheme`
+    ///
+    /// Emoji sequences and characters followed by the emoji variation selector prefer the emoji
+    /// font. Falls back to `FontId(0)` if no font covers the grapheme.
+    fn font_id_of_grapheme(&self, grapheme: &str) -> FontId {
+        let covers = |font: &FontRef| {
+            grapheme
+                .chars()
+                .filter(|&c| !is_default_ignorable(c))
+                .all(|c| font.glyph_id(c).0 != 0)
+        };
+        let prefers_emoji = grapheme.contains('\u{FE0F}') || grapheme.contains('\u{200D}');
+        if prefers_emoji && self.fonts.get(EMOJI_FONT_ID.0).is_some_and(covers) {
+            return EMOJI_FONT_ID;
+        }
+        self.fonts.iter().position(covers).map_or(FontId(0), FontId)
+    }
+
+    /// Split `text` into byte ranges of graphemes which use the same font
+    pub(super) fn split_font_runs(&self, text: &str) -> Vec<(Range<usize>, FontId)> {
+        let mut runs: Vec<(Range<usize>, FontId)> = vec![];
+        for (i, grapheme) in unic::segment::GraphemeIndices::new(text) {
+            let font_id = self.font_id_of_grapheme(grapheme);
+            match runs.last_mut() {
+                Some((range, id)) if *id == font_id => range.end = i + grapheme.len(),
+                _ => runs.push((i..i + grapheme.len(), font_id)),
+            }
+        }
+        runs
+    }
+
+    /// Shape `text` into a single line of glyphs
+    ///
+    /// The text is reordered into visual order with the Unicode bidirectional algorithm. Each run
+    /// of the same direction and font is then shaped on its own, which applies ligatures, kerning,
+    /// mark positioning and the contextual forms needed by Arabic, Devanagari, Thai and other
+    /// scripts.
+    pub(super) fn shape(&self, text: &str, font_size: f32) -> ShapedText {
+        let scale = PxScale::from(font_size);
+
+        // split the text into runs in visual order
+        let bidi = BidiInfo::new(text, None);
+        let mut runs = vec![];
+        for para in &bidi.paragraphs {
+            let (levels, level_runs) = bidi.visual_runs(para, para.range.clone());
+            for level_run in level_runs {
+                let rtl = levels[level_run.start].is_rtl();
+                let mut font_runs = self.split_font_runs(&text[level_run.clone()]);
+                // font runs of a right-to-left run are laid out from right to left too
+                if rtl {
+                    font_runs.reverse();
+                }
+                for (range, font_id) in font_runs {
+                    let range = level_run.start + range.start..level_run.start + range.end;
+                    runs.push((range, font_id, rtl));
+                }
+            }
+        }
+
+        // an empty line still takes the height of the primary font
+        let (mut ascent, mut descent) = {
+            let scaled = self.fonts[0].as_scaled(scale);
+            (scaled.ascent(), scaled.descent())
+        };
+        for (_, font_id, _) in &runs {
+            let scaled = self.fonts[font_id.0].as_scaled(scale);
+            ascent = ascent.max(scaled.ascent());
+            descent = descent.min(scaled.descent());
+        }
+        let baseline = ascent;
+
+        // shape each run, glyphs of a right-to-left run come out in visual order already
+        let mut glyphs = vec![];
+        let mut caret = 0f32;
+        for (range, font_id, rtl) in runs {
+            let mut buffer = UnicodeBuffer::new();
+            buffer.push_str(&text[range.clone()]);
+            buffer.set_direction(if rtl {
+                Direction::RightToLeft
+            } else {
+                Direction::LeftToRight
+            });
+            buffer.guess_segment_properties();
+            let output = rustybuzz::shape(&self.faces[font_id.0], &[], buffer);
+            let scaled = self.fonts[font_id.0].as_scaled(scale);
+            let (h_factor, v_factor) = (scaled.h_scale_factor(), scaled.v_scale_factor());
+            for (info, pos) in output.glyph_infos().iter().zip(output.glyph_positions()) {
+                let position = point(
+                    caret + pos.x_offset as f32 * h_factor,
+                    baseline - pos.y_offset as f32 * v_factor,
+                );
+                glyphs.push(SectionGlyph {
+                    section_index: 0,
+                    byte_index: range.start + info.cluster as usize,
+                    glyph: GlyphId(info.glyph_id as u16).with_scale_and_position(scale, position),
+                    font_id,
+                });
+                caret += pos.x_advance as f32 * h_factor;
+            }
+        }
+        ShapedText {
+            glyphs,
+            font_size,
+            width: caret,
+            ascent,
+            descent,
+        }
+    }
+
+    /// Split `text` into ranges which lines may break between
+    ///
+    /// These are the words of the text, except that a word wider than `max_w` on its own is
+    /// split into its graphemes.
+    fn split_line_break_units(&self, text: &str, max_w: f32, font_size: f32) -> Vec<Range<usize>> {
+        let mut units = vec![];
+        for (i, word) in unic::segment::WordBoundIndices::new(text) {
+            if self.shape(word, font_size).width <= max_w {
+                units.push(i..i + word.len());
+            } else {
+                units.extend(
+                    unic::segment::GraphemeIndices::new(word)
+                        .map(|(j, grapheme)| i + j..i + j + grapheme.len()),
+                );
+            }
+        }
+        units
+    }
+
+    /// Greedily wrap `text` into lines no wider than `max_w`, except for single graphemes wider
+    /// than that, and ellipsize the last line if there would be more than `max_lines`
+    pub(super) fn wrap(
+        &self,
+        text: &str,
+        max_w: f32,
+        max_lines: usize,
+        font_size: f32,
+    ) -> Vec<String> {
+        let fits = |line: &str| self.shape(line, font_size).width <= max_w;
+
+        let mut lines: Vec<Range<usize>> = vec![];
+        let mut line: Option<Range<usize>> = None;
+        for unit in self.split_line_break_units(text, max_w, font_size) {
+            let is_space = text[unit.clone()].trim().is_empty();
+            line = match line {
+                // lines never start with spaces
+                None if is_space => None,
+                None => Some(unit),
+                Some(l) if fits(text[l.start..unit.end].trim_end()) => Some(l.start..unit.end),
+                Some(l) => {
+                    lines.push(l);
+                    if is_space {
+                        None
+                    } else {
+                        Some(unit)
+                    }
+                }
+            };
+        }
+        lines.extend(line);
+
+        let mut lines: Vec<String> = if lines.len() > max_lines {
+            let rest = text[lines[max_lines - 1].start..].trim_end();
+            let mut lines: Vec<String> = lines[..max_lines - 1]
+                .iter()
+                .map(|l| text[l.clone()].to_owned())
+                .collect();
+            lines.push(self.ellipsize_always(rest, max_w, font_size));
+            lines
+        } else {
+            lines.iter().map(|l| text[l.clone()].to_owned()).collect()
+        };
+        for line in &mut lines {
+            line.truncate(line.trim_end().len());
+        }
+        lines
+    }
+
+    /// Shorten `text` to fit into `max_w` by replacing its end with an ellipsis, if it does not
+    /// fit
+    pub(super) fn ellipsize(&self, text: &str, max_w: f32, font_size: f32) -> String {
+        if self.shape(text, font_size).width <= max_w {
+            text.to_owned()
+        } else {
+            self.ellipsize_always(text, max_w, font_size)
+        }
+    }
+
+    /// Drop graphemes from the end of `text` until it fits into `max_w` with an ellipsis appended
+    fn ellipsize_always(&self, text: &str, max_w: f32, font_size: f32) -> String {
+        let mut ends: Vec<usize> = unic::segment::GraphemeIndices::new(text)
+            .map(|(i, _)| i)
+            .collect();
+        let mut ellipsized = format!("{}…", text);
+        while let Some(end) = ends.pop() {
+            ellipsized = format!("{}…", text[..end].trim_end());
+            if self.shape(&ellipsized, font_size).width <= max_w {
+                break;
+            }
+        }
+        ellipsized
+    }
+
+    /// Draw a line of shaped text with the top of the line at `(x, y)`, aligned horizontally to
+    /// it and clipped to `clip`
+    ///
+    /// The line is drawn in the color and with the effects of `style`, but keeps the font size it
+    /// was shaped at.
+    pub(super) fn draw_line(
+        &self,
+        image: &mut RgbaImage,
+        line: &ShapedText,
+        (x, y): (f32, f32),
+        align: Align,
+        clip: Clip,
+        style: &TextStyle,
+    ) -> Result<(), GenPicError> {
+        let left = align.left(x, line.width);
+        if style.stroke.is_some() || style.shadow.is_some() {
+            self.draw_effects(image, line, (left, y), clip, style)?;
+        }
+        self.draw_glyphs(image, line, (left, y), clip, style.color)
+    }
+
+    /// Draw the shadow and the outline of a line of text with its top left corner at `(x, y)`
+    ///
+    /// Both are drawn from the coverage of the glyphs, rendered onto a layer around the line
+    /// with room for the outline and the blur.
+    fn draw_effects(
+        &self,
+        image: &mut RgbaImage,
+        line: &ShapedText,
+        (x, y): (f32, f32),
+        clip: Clip,
+        style: &TextStyle,
+    ) -> Result<(), GenPicError> {
+        let stroke_w = style.stroke.map_or(0., |s| s.width.max(0.));
+        let blur_r = style.shadow.map_or(0., |s| 3. * s.blur.max(0.));
+        // glyphs may overhang their advances, such as italics and wide emoji
+        let margin = (line.height() / 2. + stroke_w + blur_r).ceil() as u32;
+        let (layer_x, layer_y) = (x.floor() - margin as f32, y.floor() - margin as f32);
+        let mut layer = RgbaImage::new(
+            line.width.ceil() as u32 + 2 * margin + 1,
+            line.height().ceil() as u32 + 2 * margin + 1,
+        );
+        let layer_clip = Clip::image(&layer);
+        let origin = (x - layer_x, y - layer_y);
+        self.draw_glyphs(&mut layer, line, origin, layer_clip, style.color)?;
+        let mut coverage = GrayImage::from_fn(layer.width(), layer.height(), |x, y| {
+            Luma([layer.get_pixel(x, y).0[3]])
+        });
+        if let Some(stroke) = style.stroke {
+            coverage = dilate(&coverage, stroke.width);
+        }
+
+        let (layer_x, layer_y) = (layer_x as i64, layer_y as i64);
+        if let Some(shadow) = style.shadow {
+            let blurred = if shadow.blur > 0. {
+                blur(&coverage, shadow.blur)
+            } else {
+                coverage.clone()
+            };
+            let (dx, dy) = shadow.offset;
+            let at = (layer_x + dx.round() as i64, layer_y + dy.round() as i64);
+            fill_clipped(image, &blurred, at, clip, shadow.color);
+        }
+        if let Some(stroke) = style.stroke {
+            fill_clipped(image, &coverage, (layer_x, layer_y), clip, stroke.color);
+        }
+        Ok(())
+    }
+
+    /// Draw the glyphs of a line of text with its top left corner at `(x, y)`, clipped to `clip`
+    fn draw_glyphs(
+        &self,
+        image: &mut RgbaImage,
+        line: &ShapedText,
+        (x, y): (f32, f32),
+        clip: Clip,
+        color: Rgba<u8>,
+    ) -> Result<(), GenPicError> {
+        for SectionGlyph { glyph, font_id, .. } in &line.glyphs {
+            let font = &self.fonts[font_id.0];
+            let mut glyph = glyph.clone();
+            glyph.position.x += x;
+            glyph.position.y += y;
+            if draw_raster_glyph(image, font, &glyph, clip)? {
+                continue;
+            }
+            if let Some(q) = font.outline_glyph(glyph) {
+                // the outline is drawn relative to the pixel its bounds start in
+                let b = q.px_bounds();
+                q.draw(|gx, gy, c| {
+                    let px = b.min.x as i64 + gx as i64;
+                    let py = b.min.y as i64 + gy as i64;
+                    let mut color = color;
+                    color.0[3] = (color.0[3] as f32 * c) as u8;
+                    blend_clipped(image, px, py, clip, &color);
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Grow `coverage` by `width` pixels in every direction, anti-aliased at the new edge
+fn dilate(coverage: &GrayImage, width: f32) -> GrayImage {
+    let r = width.max(0.).ceil() as i64 + 1;
+    let (w, h) = coverage.dimensions();
+    GrayImage::from_fn(w, h, |x, y| {
+        let mut max = 0f32;
+        for dy in -r..=r {
+            for dx in -r..=r {
+                let (sx, sy) = (x as i64 + dx, y as i64 + dy);
+                if sx < 0 || sy < 0 || sx >= w as i64 || sy >= h as i64 {
+                    continue;
+                }
+                // pixels within `width` count fully, fading out over the next pixel
+                let d = ((dx * dx + dy * dy) as f32).sqrt();
+                let weight = (width + 0.5 - d).clamp(0., 1.);
+                let c = coverage.get_pixel(sx as u32, sy as u32).0[0] as f32;
+                max = max.max(c * weight);
+            }
+        }
+        Luma([max.round() as u8])
+    })
+}
+
+/// Blend `color` onto `image` with its top left corner at `(x, y)`, weighted by `coverage`
+fn fill_clipped(
+    image: &mut RgbaImage,
+    coverage: &GrayImage,
+    (x, y): (i64, i64),
+    clip: Clip,
+    color: Rgba<u8>,
+) {
+    for (cx, cy, c) in coverage.enumerate_pixels() {
+        if c.0[0] == 0 {
+            continue;
+        }
+        let mut color = color;
+        color.0[3] = (color.0[3] as u32 * c.0[0] as u32 / 255) as u8;
+        blend_clipped(image, x + cx as i64, y + cy as i64, clip, &color);
+    }
+}
+
+/// Characters which modify the grapheme they are in and need no glyph of their own
+fn is_default_ignorable(c: char) -> bool {
+    matches!(c,
+        '\u{200B}'..='\u{200F}' // zero width space, joiners and direction marks
+        | '\u{FE00}'..='\u{FE0F}' // variation selectors
+        | '\u{E0000}'..='\u{E007F}' // tags
+        | '\u{E0100}'..='\u{E01EF}' // variation selectors supplement
+    )
+}
+
+/// Blend `color` onto the pixel at `(x, y)`, unless it is outside of `clip` or the image
+fn blend_clipped(image: &mut RgbaImage, x: i64, y: i64, clip: Clip, color: &Rgba<u8>) {
+    if clip.contains(x, y) && Clip::image(image).contains(x, y) {
+        blend_over(image.get_pixel_mut(x as u32, y as u32), color);
+    }
+}
+
+/// Draw the bitmap of a color emoji glyph, scaled to the size of the glyph
+///
+/// Returns `false` if the font has no bitmap for the glyph.
+fn draw_raster_glyph(
+    image: &mut RgbaImage,
+    font: &FontRef,
+    glyph: &Glyph,
+    clip: Clip,
+) -> Result<bool, GenPicError> {
+    let gi = match font.glyph_raster_image(glyph.id, u16::MAX) {
+        Some(gi) => gi,
+        None => return Ok(false),
+    };
+    // only PNG bitmaps are used by the Noto Color Emoji font
+    if !matches!(gi.format, GlyphImageFormat::Png) {
+        return Ok(false);
+    }
+    let bitmap = image::load_from_memory_with_format(gi.data, ImageFormat::Png)?.into_rgba8();
+    // the bitmap is drawn at `gi.scale` pixels per em, rescale it to the pixels per em of the glyph
+    let ppem =
+        font.as_scaled(glyph.scale).h_scale_factor() * font.units_per_em().unwrap_or(gi.scale);
+    let s = ppem / gi.scale;
+    let w = ((bitmap.width() as f32 * s).round() as u32).max(1);
+    let h = ((bitmap.height() as f32 * s).round() as u32).max(1);
+    let bitmap = resize_premultiplied(&bitmap, w, h);
+    // the origin is the bottom left corner of the bitmap relative to the baseline
+    let left = (glyph.position.x + gi.origin.x * s).round() as i64;
+    let top = (glyph.position.y - gi.origin.y * s - h as f32).round() as i64;
+    for (x, y, p) in bitmap.enumerate_pixels() {
+        blend_clipped(image, left + x as i64, top + y as i64, clip, p);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn font_runs_keep_graphemes_together() {
+        let fonts = Fonts::get().unwrap();
+        let text = "ラブライブ! Café 가나다 สวัสดี 👨‍👩‍👧 e\u{301}";
+        let runs = fonts.split_font_runs(text);
+        assert_eq!(
+            runs.iter()
+                .map(|(range, _)| &text[range.clone()])
+                .collect::<String>(),
+            text
+        );
+        for (range, font_id) in &runs {
+            assert!(unic::segment::Graphemes::new(&text[range.clone()])
+                .all(|g| fonts.font_id_of_grapheme(g) == *font_id));
+        }
+        assert!(runs.windows(2).all(|w| w[0].1 != w[1].1));
+    }
+
+    #[test]
+    fn shape_right_to_left_text() {
+        let fonts = Fonts::get().unwrap();
+        // the Hebrew word is laid out after the Latin word, from its last letter to its first
+        let text = "abc שלום";
+        let glyphs = fonts.shape(text, 54.).glyphs;
+        let byte_indices: Vec<_> = glyphs.iter().map(|g| g.byte_index).collect();
+        assert_eq!(byte_indices, vec![0, 1, 2, 3, 10, 8, 6, 4]);
+        assert!(glyphs
+            .windows(2)
+            .all(|w| w[0].glyph.position.x <= w[1].glyph.position.x));
+    }
+
+    #[test]
+    fn wrap_text_ellipsizes_last_line() {
+        let fonts = Fonts::get().unwrap();
+        let lines = fonts.wrap("aaa bbb ccc ddd", 100., 2, 32.);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].ends_with('…'));
+        assert!(lines.iter().all(|l| fonts.shape(l, 32.).width <= 100.));
+    }
+
+    #[test]
+    fn ellipsize_caption() {
+        let fonts = Fonts::get().unwrap();
+        assert_eq!(fonts.ellipsize("Ai", 120., 18.), "Ai");
+        let caption = fonts.ellipsize("Shizuku Osaka the Third of Her Name", 120., 18.);
+        assert!(caption.ends_with('…'));
+        assert!(fonts.shape(&caption, 18.).width <= 120.);
+    }
+
+    #[test]
+    fn measure_scales_with_font_size_and_kerns() {
+        let white = Rgba([255, 255, 255, 255]);
+        let small = measure("Love Live!", &TextStyle::new(20., white)).unwrap();
+        let large = measure("Love Live!", &TextStyle::new(40., white)).unwrap();
+        assert!((large.width - 2. * small.width).abs() < 0.5);
+        assert!((large.height() - 2. * small.height()).abs() < 0.5);
+        assert_eq!(measure("", &TextStyle::new(20., white)).unwrap().width, 0.);
+
+        // kerning pulls the pair closer than the letters on their own
+        let style = TextStyle::new(54., white);
+        let pair = measure("AV", &style).unwrap().width;
+        let apart = measure("A", &style).unwrap().width + measure("V", &style).unwrap().width;
+        assert!(pair < apart);
+    }
+
+    #[test]
+    fn draw_aligns_to_anchor() {
+        let style = TextStyle::new(32., Rgba([255, 255, 255, 255]));
+        let inked_columns = |align: Align, x: f32| {
+            let mut image = RgbaImage::new(300, 50);
+            draw(&mut image, "Ai", &style, (x, 0.), align).unwrap();
+            let columns: Vec<u32> = (0..300)
+                .filter(|&x| (0..50).any(|y| image.get_pixel(x, y).0[3] > 0))
+                .collect();
+            (columns[0], columns[columns.len() - 1])
+        };
+        let bounds = measure("Ai", &style).unwrap();
+        let (left, _) = inked_columns(Align::Left, 100.);
+        let (center_left, center_right) = inked_columns(Align::Center, 150.);
+        let (_, right) = inked_columns(Align::Right, 200.);
+        assert!((100..110).contains(&left));
+        assert!(center_left < 150 && center_right > 150);
+        assert!((190..200).contains(&right));
+        assert!(center_right - center_left <= bounds.width as u32 + 1);
+    }
+
+    #[test]
+    fn draw_clips_without_panicking() {
+        let style = TextStyle::new(54., Rgba([255, 255, 255, 255]));
+        let mut image = RgbaImage::new(40, 20);
+        for &anchor in &[
+            (-30., -30.),
+            (20., -10.),
+            (35., 15.),
+            (1e6, 1e6),
+            (-1e6, 0.),
+        ] {
+            for &align in &[Align::Left, Align::Center, Align::Right] {
+                draw(&mut image, "虹 Ai 🔥", &style, anchor, align).unwrap();
+            }
+        }
+
+        let mut image = RgbaImage::new(200, 100);
+        let clip = Clip {
+            x: 50,
+            y: 20,
+            w: 100,
+            h: 30,
+        };
+        draw_clipped(
+            &mut image,
+            "Nijigasaki",
+            &style,
+            (100., 0.),
+            Align::Center,
+            clip,
+        )
+        .unwrap();
+        assert!(image
+            .enumerate_pixels()
+            .filter(|(_, _, p)| p.0[3] > 0)
+            .all(|(x, y, _)| clip.contains(x as i64, y as i64)));
+        assert!(image.pixels().any(|p| p.0[3] > 0));
+    }
+
+    #[test]
+    fn stroke_and_shadow_surround_text() {
+        let white = Rgba([255, 255, 255, 255]);
+        let black = Rgba([0, 0, 0, 255]);
+        let render = |style: &TextStyle| {
+            let mut image = RgbaImage::new(200, 80);
+            draw(&mut image, "Ai", style, (100., 10.), Align::Center).unwrap();
+            image
+        };
+        let inked = |image: &RgbaImage, x: u32, y: u32| image.get_pixel(x, y).0[3] > 0;
+        let plain = render(&TextStyle::new(32., white));
+
+        // the outline covers the text and more, with the glyphs on top
+        let stroked = render(&TextStyle {
+            stroke: Some(Stroke {
+                width: 2.,
+                color: black,
+            }),
+            ..TextStyle::new(32., white)
+        });
+        let count = |image: &RgbaImage| image.pixels().filter(|p| p.0[3] > 0).count();
+        assert!(count(&stroked) > count(&plain));
+        for (x, y, p) in plain.enumerate_pixels() {
+            assert!(!inked(&plain, x, y) || inked(&stroked, x, y));
+            if p.0[3] == 255 {
+                assert_eq!(stroked.get_pixel(x, y), p);
+            }
+        }
+
+        // a hard shadow is the text moved by the offset
+        let shadowed = render(&TextStyle {
+            shadow: Some(Shadow {
+                offset: (4., 4.),
+                blur: 0.,
+                color: black,
+            }),
+            ..TextStyle::new(32., white)
+        });
+        let (x, y, _) = plain
+            .enumerate_pixels()
+            .find(|&(x, y, p)| p.0[3] == 255 && !inked(&plain, x + 4, y + 4))
+            .unwrap();
+        assert_eq!(*shadowed.get_pixel(x + 4, y + 4), black);
+    }
+}
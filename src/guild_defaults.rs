@@ -0,0 +1,144 @@
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+use anyhow::anyhow;
+use groupic::gen_pic::Theme;
+use twilight_model::id::{marker::GuildMarker, Id};
+
+type GuildId = Id<GuildMarker>;
+
+/// Where the background image of the group picture comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BackgroundSource {
+    /// Flat background color of the theme
+    None,
+    /// Image uploaded for the guild to `GROUPIC_BACKGROUNDS_DIR`
+    Upload,
+    /// Banner of the guild
+    Banner,
+    /// Invite splash of the guild
+    Splash,
+    /// Cover image of the scheduled event going on in the voice channel
+    EventCover,
+}
+
+impl BackgroundSource {
+    pub const ALL: [BackgroundSource; 5] = [
+        BackgroundSource::None,
+        BackgroundSource::Upload,
+        BackgroundSource::Banner,
+        BackgroundSource::Splash,
+        BackgroundSource::EventCover,
+    ];
+}
+
+impl AsRef<str> for BackgroundSource {
+    fn as_ref(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Upload => "upload",
+            Self::Banner => "banner",
+            Self::Splash => "splash",
+            Self::EventCover => "event-cover",
+        }
+    }
+}
+
+impl FromStr for BackgroundSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|source| source.as_ref() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("unknown background source {}", s))
+    }
+}
+
+/// Defaults of the `/groupic` options for each guild
+#[derive(Debug, Default)]
+pub struct GuildDefaults {
+    themes: HashMap<GuildId, Theme>,
+    backgrounds_dir: Option<PathBuf>,
+    templates_dir: Option<PathBuf>,
+}
+
+impl GuildDefaults {
+    /// Read the defaults from the environment
+    ///
+    /// `GROUPIC_GUILD_THEMES` is a comma separated list of `<guild_id>=<theme>`, e.g.
+    /// `81384788765712384=discord-light,613425648685547541=amoled-black`.
+    ///
+    /// `GROUPIC_BACKGROUNDS_DIR` is a directory of background images uploaded for guilds, each
+    /// named after its guild id, e.g. `81384788765712384.png`.
+    ///
+    /// `GROUPIC_TEMPLATES_DIR` is a directory of picture templates in TOML or JSON, each named
+    /// after its guild id for the default of that guild, with a `shared` subdirectory of
+    /// templates any guild can pick by name.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut themes = HashMap::new();
+        if let Ok(var) = std::env::var("GROUPIC_GUILD_THEMES") {
+            for entry in var.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                let (guild_id, theme) = entry.split_once('=').ok_or_else(|| {
+                    anyhow!(
+                        "Expected <guild_id>=<theme> in GROUPIC_GUILD_THEMES but got {}",
+                        entry
+                    )
+                })?;
+                let guild_id = GuildId::new_checked(guild_id.trim().parse::<u64>()?)
+                    .ok_or_else(|| anyhow!("Invalid guild id in GROUPIC_GUILD_THEMES"))?;
+                themes.insert(guild_id, theme.trim().parse::<Theme>()?);
+            }
+        }
+        let backgrounds_dir = std::env::var_os("GROUPIC_BACKGROUNDS_DIR").map(PathBuf::from);
+        let templates_dir = std::env::var_os("GROUPIC_TEMPLATES_DIR").map(PathBuf::from);
+        Ok(Self {
+            themes,
+            backgrounds_dir,
+            templates_dir,
+        })
+    }
+
+    /// Theme of the guild, or Discord dark if the guild has none
+    pub fn theme(&self, guild_id: GuildId) -> Theme {
+        self.themes
+            .get(&guild_id)
+            .copied()
+            .unwrap_or(Theme::DiscordDark)
+    }
+
+    /// Background image uploaded for the guild, if any
+    pub fn background(&self, guild_id: GuildId) -> Option<PathBuf> {
+        let dir = self.backgrounds_dir.as_ref()?;
+        ["png", "jpg", "jpeg", "webp"]
+            .iter()
+            .map(|ext| dir.join(format!("{}.{}", guild_id, ext)))
+            .find(|path| path.is_file())
+    }
+
+    /// Shared template named `name`, or the default template of the guild if no name is given
+    ///
+    /// Names are only looked up among the shared templates, so that a guild cannot pick the
+    /// default of another guild by its id. The file is looked up on every call so templates can
+    /// be edited without a restart.
+    pub fn template(&self, guild_id: GuildId, name: Option<&str>) -> Option<PathBuf> {
+        let dir = self.templates_dir.as_ref()?;
+        let (dir, name) = match name {
+            Some(name) if is_template_name(name) => (dir.join("shared"), name.to_owned()),
+            Some(_) => return None,
+            None => (dir.to_owned(), guild_id.to_string()),
+        };
+        ["toml", "json"]
+            .iter()
+            .map(|ext| dir.join(format!("{}.{}", name, ext)))
+            .find(|path| path.is_file())
+    }
+}
+
+/// Whether `name` names a file in the shared templates directory rather than a path out of it
+fn is_template_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
@@ -1,18 +1,20 @@
 mod alias;
-mod gen_pic;
+mod guild_defaults;
+mod order;
 mod util;
 
-use anyhow::Context;
+use std::convert::TryFrom;
+
 use futures::future::try_join_all;
-use hyper::body::HttpBody;
-use tempdir::TempDir;
-use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use rayon::prelude::*;
 use tokio::task::spawn_blocking;
 use tokio_stream::StreamExt;
 use tracing::{error, info};
 
 use alias::*;
+use groupic::gen_pic;
+use guild_defaults::{BackgroundSource, GuildDefaults};
+use order::{AvatarOrder, JoinOrder};
 use twilight_http::request::AttachmentFile;
 use util::*;
 
@@ -20,7 +22,8 @@ use twilight_cache_inmemory::{InMemoryCache, ResourceType};
 use twilight_gateway::{Event, EventTypeFlags, Intents, Shard};
 use twilight_model::application::callback::InteractionResponse;
 use twilight_model::application::command::{
-    self, ChannelCommandOptionData, Command, CommandOption, CommandType, NumberCommandOptionData,
+    self, BaseCommandOptionData, ChannelCommandOptionData, ChoiceCommandOptionData, Command,
+    CommandOption, CommandOptionChoice, CommandType, NumberCommandOptionData,
 };
 use twilight_model::application::interaction::application_command::CommandOptionValue;
 use twilight_model::application::interaction::Interaction;
@@ -28,6 +31,7 @@ use twilight_model::channel::message::MessageFlags;
 use twilight_model::channel::{Channel, ChannelType, GuildChannel};
 use twilight_model::guild::Member;
 use twilight_model::id::{marker::ApplicationMarker, Id};
+use twilight_model::scheduled_event::Status;
 use twilight_util::builder::command::CommandBuilder;
 
 type ApplicationId = Id<ApplicationMarker>;
@@ -55,6 +59,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let application_id = ApplicationId::new_checked(application_id)
         .ok_or(anyhow::anyhow!("Invalid application id in DISCORD_APP_ID"))?;
 
+    let guild_defaults = GuildDefaults::from_env()?;
+
     let hc = twilight_http::Client::builder()
         .token(token.clone())
         .build();
@@ -96,6 +102,171 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 required: false,
                 autocomplete: false,
             }))
+            .option(CommandOption::String(ChoiceCommandOptionData {
+                autocomplete: false,
+                choices: AvatarOrder::ALL
+                    .iter()
+                    .map(|order| CommandOptionChoice::String {
+                        name: order.as_ref().into(),
+                        value: order.as_ref().into(),
+                    })
+                    .collect(),
+                description: "Order of the avatars, by join order by default".into(),
+                name: "order".into(),
+                required: false,
+            }))
+            .option(CommandOption::User(BaseCommandOptionData {
+                description: "Member to put first, such as the host".into(),
+                name: "host".into(),
+                required: false,
+            }))
+            .option(CommandOption::Integer(NumberCommandOptionData {
+                choices: gen_pic::TILE_SIZES
+                    .iter()
+                    .map(|&size| CommandOptionChoice::Int {
+                        name: size.to_string(),
+                        value: size.into(),
+                    })
+                    .collect(),
+                min_value: None,
+                max_value: None,
+                description: "Width and height of each avatar in pixels".into(),
+                name: "tile-size".into(),
+                required: false,
+                autocomplete: false,
+            }))
+            .option(CommandOption::Boolean(BaseCommandOptionData {
+                description: "Show the name of each member under their avatar".into(),
+                name: "names".into(),
+                required: false,
+            }))
+            .option(CommandOption::Boolean(BaseCommandOptionData {
+                description: "Show who is muted, deafened, streaming or on camera".into(),
+                name: "badges".into(),
+                required: false,
+            }))
+            .option(CommandOption::String(ChoiceCommandOptionData {
+                autocomplete: false,
+                choices: vec![],
+                description:
+                    "Ring around each avatar: role for the role color, or a color like #5865f2"
+                        .into(),
+                name: "ring".into(),
+                required: false,
+            }))
+            .option(CommandOption::Boolean(BaseCommandOptionData {
+                description: "Group members into sections by their highest hoisted role".into(),
+                name: "sections".into(),
+                required: false,
+            }))
+            .option(CommandOption::Boolean(BaseCommandOptionData {
+                description: "Show the guild icon, the date, the member count and the guild name"
+                    .into(),
+                name: "guild-info".into(),
+                required: false,
+            }))
+            .option(CommandOption::String(ChoiceCommandOptionData {
+                autocomplete: false,
+                choices: gen_pic::Theme::ALL
+                    .iter()
+                    .map(|theme| CommandOptionChoice::String {
+                        name: theme.as_ref().into(),
+                        value: theme.as_ref().into(),
+                    })
+                    .collect(),
+                description: "Colors of the group picture".into(),
+                name: "theme".into(),
+                required: false,
+            }))
+            .option(CommandOption::String(ChoiceCommandOptionData {
+                autocomplete: false,
+                choices: gen_pic::MaskShape::ALL
+                    .iter()
+                    .map(|shape| CommandOptionChoice::String {
+                        name: shape.as_ref().into(),
+                        value: shape.as_ref().into(),
+                    })
+                    .collect(),
+                description: "Shape of each avatar".into(),
+                name: "shape".into(),
+                required: false,
+            }))
+            .option(CommandOption::String(ChoiceCommandOptionData {
+                autocomplete: false,
+                choices: gen_pic::Layout::ALL
+                    .iter()
+                    .map(|layout| CommandOptionChoice::String {
+                        name: layout.as_ref().into(),
+                        value: layout.as_ref().into(),
+                    })
+                    .collect(),
+                description: "Arrangement of the avatars".into(),
+                name: "layout".into(),
+                required: false,
+            }))
+            .option(CommandOption::String(ChoiceCommandOptionData {
+                autocomplete: false,
+                choices: BackgroundSource::ALL
+                    .iter()
+                    .map(|source| CommandOptionChoice::String {
+                        name: source.as_ref().into(),
+                        value: source.as_ref().into(),
+                    })
+                    .collect(),
+                description: "Image behind the avatars, the uploaded one if any by default".into(),
+                name: "background".into(),
+                required: false,
+            }))
+            .option(CommandOption::String(ChoiceCommandOptionData {
+                autocomplete: false,
+                choices: gen_pic::TextEffect::ALL
+                    .iter()
+                    .map(|effect| CommandOptionChoice::String {
+                        name: effect.as_ref().into(),
+                        value: effect.as_ref().into(),
+                    })
+                    .collect(),
+                description:
+                    "Effect keeping the text readable, a shadow over backgrounds by default".into(),
+                name: "text-effect".into(),
+                required: false,
+            }))
+            .option(CommandOption::String(ChoiceCommandOptionData {
+                autocomplete: false,
+                choices: vec![],
+                description:
+                    "Picture template to start from, the one of the guild if any by default".into(),
+                name: "template".into(),
+                required: false,
+            }))
+            .option(CommandOption::Boolean(BaseCommandOptionData {
+                description: "Leave the background transparent".into(),
+                name: "transparent".into(),
+                required: false,
+            }))
+            .option(CommandOption::Boolean(BaseCommandOptionData {
+                description: "Animate the avatars of Nitro members as a GIF".into(),
+                name: "animated".into(),
+                required: false,
+            }))
+            .option(CommandOption::String(ChoiceCommandOptionData {
+                autocomplete: false,
+                choices: std::iter::once("auto")
+                    .chain(
+                        gen_pic::OutputFormat::ALL
+                            .iter()
+                            .map(|format| format.as_ref()),
+                    )
+                    .map(|format| CommandOptionChoice::String {
+                        name: format.into(),
+                        value: format.into(),
+                    })
+                    .collect(),
+                description: "Encoding of the group picture, or auto to fit the upload limit"
+                    .into(),
+                name: "format".into(),
+                required: false,
+            }))
             .build(),
         ])
         .exec()
@@ -136,12 +307,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     gc.start().await?;
 
     let cache = InMemoryCache::builder()
-        .resource_types(ResourceType::GUILD | ResourceType::VOICE_STATE)
+        .resource_types(ResourceType::GUILD | ResourceType::VOICE_STATE | ResourceType::ROLE)
         .build();
+    let mut join_order = JoinOrder::default();
 
     while let Some(event) = events.next().await {
         cache.update(&event);
         match event {
+            Event::VoiceStateUpdate(x) => join_order.update(&x.0),
             Event::Ready(x) => {
                 let me = x.user;
                 info!(
@@ -185,6 +358,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                                                 m.user.unwrap().id,
                                                 member_avatar,
                                                 cdn::PJWG::PNG,
+                                                None,
                                             )
                                         }
                                     }
@@ -196,8 +370,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                                                 u.id,
                                                 user_avatar,
                                                 cdn::PJWG::PNG,
+                                                None,
                                             ),
-                                            None => cdn::get_default_user_avatar(u.discriminator),
+                                            None => {
+                                                cdn::get_default_user_avatar(u.discriminator, None)
+                                            }
                                         },
                                         // get default avatar otherwise
                                         None => {
@@ -209,10 +386,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                                 None => {
                                     let u = ac.user.unwrap();
                                     match u.avatar {
-                                        Some(user_avatar) => {
-                                            cdn::get_user_avatar(u.id, user_avatar, cdn::PJWG::PNG)
-                                        }
-                                        None => cdn::get_default_user_avatar(u.discriminator),
+                                        Some(user_avatar) => cdn::get_user_avatar(
+                                            u.id,
+                                            user_avatar,
+                                            cdn::PJWG::PNG,
+                                            None,
+                                        ),
+                                        None => cdn::get_default_user_avatar(u.discriminator, None),
                                     }
                                 }
                             };
@@ -285,14 +465,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                                     continue;
                                 }
                             };
-                            let mut v_m: Vec<_> = Vec::with_capacity(1 << 5); // 128
+                            // downloading and rendering can take longer than Discord waits for a
+                            // response, so answer now and attach the picture once it is ready
+                            let cbd = twilight_util::builder::CallbackDataBuilder::new().build();
+                            ic.create_interaction_original(
+                                ac.id,
+                                &ac.token,
+                                &InteractionResponse::DeferredChannelMessageWithSource(cbd),
+                            )
+                            .exec()
+                            .await?;
+                            let mut v_mb: Vec<_> = Vec::with_capacity(1 << 5); // 128
                             for vs in voice_states.inspect(|vs| {
                                 dbg_trace!(vs.user_id);
                             }) {
                                 if vs.channel_id.unwrap() == ci {
+                                    let b = gen_pic::VoiceBadges {
+                                        self_mute: vs.self_mute,
+                                        self_deaf: vs.self_deaf,
+                                        server_mute: vs.mute,
+                                        streaming: vs.self_stream,
+                                        video: vs.self_video,
+                                    };
                                     match vs.member.clone() {
                                         Some(m) => {
-                                            v_m.push(m);
+                                            v_mb.push((m, b));
                                         }
                                         None => {
                                             let m: Member = hc
@@ -301,17 +498,62 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                                                 .await?
                                                 .model()
                                                 .await?;
-                                            v_m.push(m);
+                                            v_mb.push((m, b));
                                         }
                                     }
                                 }
                             }
+
+                            // voice states come from the cache in no particular order
+                            let avatar_order = options
+                                .iter()
+                                .find(|cdo| cdo.name == "order")
+                                .and_then(|cdo| match &cdo.value {
+                                    CommandOptionValue::String(x) => x
+                                        .parse::<AvatarOrder>()
+                                        .map_err(|e| error!("{}", e))
+                                        .ok(),
+                                    _ => {
+                                        error!("Should get string for order but instead got something else");
+                                        None
+                                    }
+                                })
+                                .unwrap_or_default();
+                            let host = options
+                                .iter()
+                                .find(|cdo| cdo.name == "host")
+                                .and_then(|cdo| match cdo.value {
+                                    CommandOptionValue::User(x) => Some(x),
+                                    _ => {
+                                        error!("Should get user for host but instead got something else");
+                                        None
+                                    }
+                                });
+                            order::sort_members(
+                                &mut v_mb,
+                                avatar_order,
+                                host,
+                                &join_order,
+                                |role_id| cache.role(role_id).map(|role| role.resource().position),
+                            );
+                            let show_sections = options
+                                .iter()
+                                .find(|cdo| cdo.name == "sections")
+                                .is_some_and(|cdo| match cdo.value {
+                                    CommandOptionValue::Boolean(x) => x,
+                                    _ => {
+                                        error!("Should get boolean for sections but instead got something else");
+                                        false
+                                    }
+                                });
+                            let sections = show_sections.then(|| {
+                                order::group_by_hoisted_role(&mut v_mb, |role_id| {
+                                    cache.role(role_id).map(|role| role.resource().clone())
+                                })
+                            });
+                            let (v_m, v_b): (Vec<_>, Vec<_>) = v_mb.into_iter().unzip();
                             dbg_trace!(&v_m);
-                            
 
-                            // download avatars to this temp dir
-                            let avatars_dir = TempDir::new("avatars").unwrap();
-                            let avatars_dir_path = avatars_dir.path().to_owned();
                             // construct async download tasks for each image file
                             let https = hyper_rustls::HttpsConnectorBuilder::new()
                                 .with_native_roots()
@@ -321,6 +563,78 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                                 .build();
                             let rc: hyper::Client<_, hyper::Body> =
                                 hyper::Client::builder().build(https);
+                            let animated = options
+                                .iter()
+                                .find(|cdo| cdo.name == "animated")
+                                .is_some_and(|cdo| match cdo.value {
+                                    CommandOptionValue::Boolean(x) => x,
+                                    _ => {
+                                        error!("Should get boolean for animated but instead got something else");
+                                        false
+                                    }
+                                });
+                            // options given with the command override the template
+                            let template_name = options
+                                .iter()
+                                .find(|cdo| cdo.name == "template")
+                                .and_then(|cdo| match &cdo.value {
+                                    CommandOptionValue::String(x) => Some(x.clone()),
+                                    _ => {
+                                        error!("Should get string for template but instead got something else");
+                                        None
+                                    }
+                                });
+                            let template_path =
+                                guild_defaults.template(gi, template_name.as_deref());
+                            if let (Some(name), None) = (&template_name, &template_path) {
+                                error!(
+                                    "There is no shared template named {} for guild {}",
+                                    name, gi
+                                );
+                            }
+                            // read on every command so that edited templates apply right away
+                            let template_config = match template_path {
+                                Some(path) => {
+                                    let title = vc.name.clone();
+                                    let avatar_count = v_m.len();
+                                    spawn_blocking(move || {
+                                        gen_pic::template::Template::load(&path)?
+                                            .config(&title, avatar_count)
+                                    })
+                                    .await?
+                                    .map_err(|e| error!("{}", e))
+                                    .ok()
+                                }
+                                None => None,
+                            };
+                            let mut base =
+                                template_config.unwrap_or_else(|| gen_pic::GroupPicConfig {
+                                    header_text: vc.name.clone(),
+                                    style: guild_defaults.theme(gi).style(),
+                                    ..Default::default()
+                                });
+                            // request avatars from the CDN at the size they are drawn
+                            let tile_size = options
+                                .iter()
+                                .find(|cdo| cdo.name == "tile-size")
+                                .and_then(|cdo| match cdo.value {
+                                    CommandOptionValue::Integer(x) => u32::try_from(x).ok(),
+                                    _ => {
+                                        error!("Should get integer for tile-size but instead got something else");
+                                        None
+                                    }
+                                })
+                                .unwrap_or(base.tile_size);
+                            // animated avatars have hashes prefixed with a_
+                            let avatar_format = |avatar_hash: String| {
+                                if animated && avatar_hash.starts_with("a_") {
+                                    cdn::PJWG::GIF
+                                } else {
+                                    cdn::PJWG::PNG
+                                }
+                            };
+                            // for the guild icon and the background image
+                            let asset_rc = rc.clone();
                             let download_futs: Vec<_> = v_m
                                 .iter()
                                 .map(|m| match m.avatar.as_ref() {
@@ -328,46 +642,109 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                                         gi,
                                         m.user.id,
                                         s,
-                                        cdn::PJWG::PNG,
+                                        avatar_format(s.to_string()),
+                                        Some(tile_size),
                                     ),
                                     None => match m.user.avatar.as_ref() {
-                                        Some(s) => {
-                                            cdn::get_user_avatar(m.user.id, s, cdn::PJWG::PNG)
-                                        }
-                                        None => cdn::get_default_user_avatar(m.user.discriminator),
+                                        Some(s) => cdn::get_user_avatar(
+                                            m.user.id,
+                                            s,
+                                            avatar_format(s.to_string()),
+                                            Some(tile_size),
+                                        ),
+                                        None => cdn::get_default_user_avatar(
+                                            m.user.discriminator,
+                                            Some(tile_size),
+                                        ),
                                     },
                                 })
                                 .map(move |url| {
-                                    let avatars_dir_path = avatars_dir_path.clone();
                                     let rc = rc.clone();
                                     async move {
-                                        let uri: hyper::Uri = url.parse().unwrap();
-                                        let mut file = {
-                                            let fname = std::path::Path::new(uri.path())
-                                                .file_name()
-                                                .unwrap();
-                                            fs::File::create(avatars_dir_path.join(fname))
-                                                .await
-                                                .unwrap()
-                                        };
-                                        let mut res = rc.get(uri).await?;
-                                        while let Some(chunk) = res.body_mut().data().await {
-                                            file.write_all(&chunk?).await?;
+                                        let uri: hyper::Uri = url.parse()?;
+                                        let res = rc.get(uri).await?;
+                                        if !res.status().is_success() {
+                                            return Err(anyhow::anyhow!(
+                                                "{} answered {}",
+                                                url,
+                                                res.status()
+                                            ));
                                         }
-                                        Result::<_, anyhow::Error>::Ok(())
+                                        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+                                        Result::<_, anyhow::Error>::Ok(bytes)
                                     }
                                 })
                                 .collect();
-                            // run downloads concurrently
-                            try_join_all(download_futs).await?;
+                            // run downloads concurrently, keeping the order of the members
+                            let avatars_bytes = match try_join_all(download_futs).await {
+                                Ok(avatars_bytes) => avatars_bytes,
+                                Err(e) => {
+                                    error!("Failed to download the avatars in {}: {}", vc.name, e);
+                                    let content =
+                                        format!("Failed to draw the group picture: {}", e);
+                                    ic.update_interaction_original(&ac.token)
+                                        .content(Some(&content))?
+                                        .exec()
+                                        .await?;
+                                    continue;
+                                }
+                            };
 
-                            let groupic_path = avatars_dir.path().join("groupic.png");
+                            let guild_info = options
+                                .iter()
+                                .find(|cdo| cdo.name == "guild-info")
+                                .is_some_and(|cdo| match cdo.value {
+                                    CommandOptionValue::Boolean(x) => x,
+                                    _ => {
+                                        error!("Should get boolean for guild-info but instead got something else");
+                                        false
+                                    }
+                                });
+                            let guild = cache
+                                .guild(gi)
+                                .filter(|_| guild_info)
+                                .map(|g| (g.name().to_owned(), g.icon().map(|h| h.to_string())));
+                            // the date the command was used on, and the members in the picture
+                            let date = snowflake_date(ac.id.get());
+                            let subtitle = guild.as_ref().map(|(name, _)| {
+                                format!("{} · {} members · {}", date, v_m.len(), name)
+                            });
+                            let header_icon = match guild.and_then(|(_, icon)| icon) {
+                                Some(icon) => {
+                                    // the icon is drawn at less than 64 pixels tall
+                                    let url =
+                                        cdn::get_guild_icon(gi, icon, cdn::PJWG::PNG, Some(64));
+                                    let bytes = async {
+                                        let uri: hyper::Uri = url.parse()?;
+                                        let res = asset_rc.get(uri).await?;
+                                        if !res.status().is_success() {
+                                            return Err(anyhow::anyhow!(
+                                                "{} answered {}",
+                                                url,
+                                                res.status()
+                                            ));
+                                        }
+                                        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+                                        Result::<_, anyhow::Error>::Ok(bytes)
+                                    };
+                                    // the header goes without the icon if it cannot be had
+                                    bytes
+                                        .await
+                                        .map_err(|e| {
+                                            error!("Failed to get the icon of guild {}: {}", gi, e)
+                                        })
+                                        .ok()
+                                        .and_then(|bytes| {
+                                            gen_pic::decode_avatar_frames(&bytes)
+                                                .map_err(|e| error!("{}", e))
+                                                .ok()
+                                        })
+                                        .and_then(|frames| frames.into_iter().next())
+                                        .map(|frame| frame.into_buffer())
+                                }
+                                None => None,
+                            };
 
-                            let ad_clone = avatars_dir.path().to_owned();
-                            let vn_clone = vc.name.clone();
-                            let gp_clone = groupic_path.clone();
-                            dbg_debug!(&avatars_dir.path().is_dir());
-                            use std::convert::TryFrom;
                             let column_count = options
                                 .iter_mut()
                                 .find(|cdo| cdo.name == "column-count")
@@ -380,17 +757,343 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                                         error!("Should get integer for column-count but instead got something else");
                                         None
                                     }
+                                })
+                                .or(base.num_of_avatars_in_a_row);
+                            let show_names = options
+                                .iter()
+                                .find(|cdo| cdo.name == "names")
+                                .is_some_and(|cdo| match cdo.value {
+                                    CommandOptionValue::Boolean(x) => x,
+                                    _ => {
+                                        error!("Should get boolean for names but instead got something else");
+                                        false
+                                    }
                                 });
-                            spawn_blocking(move || {
-                                gen_pic::generate_group_pic(
-                                    ad_clone,
-                                    gp_clone,
-                                    column_count,
-                                    vn_clone,
-                                );
+                            let transparent = options
+                                .iter()
+                                .find(|cdo| cdo.name == "transparent")
+                                .and_then(|cdo| match cdo.value {
+                                    CommandOptionValue::Boolean(x) => Some(x),
+                                    _ => {
+                                        error!("Should get boolean for transparent but instead got something else");
+                                        None
+                                    }
+                                })
+                                .unwrap_or(base.transparent);
+                            let show_badges = options
+                                .iter()
+                                .find(|cdo| cdo.name == "badges")
+                                .is_some_and(|cdo| match cdo.value {
+                                    CommandOptionValue::Boolean(x) => x,
+                                    _ => {
+                                        error!("Should get boolean for badges but instead got something else");
+                                        false
+                                    }
+                                });
+                            // caption each avatar with the display name of the member
+                            let captions = show_names.then(|| {
+                                v_m.iter()
+                                    .map(|m| m.nick.clone().unwrap_or_else(|| m.user.name.clone()))
+                                    .collect()
+                            });
+                            let badges = show_badges.then_some(v_b);
+                            let style = options
+                                .iter()
+                                .find(|cdo| cdo.name == "theme")
+                                .and_then(|cdo| match &cdo.value {
+                                    CommandOptionValue::String(x) => x
+                                        .parse::<gen_pic::Theme>()
+                                        .map_err(|e| error!("{}", e))
+                                        .ok(),
+                                    _ => {
+                                        error!("Should get string for theme but instead got something else");
+                                        None
+                                    }
+                                })
+                                .map_or(base.style, |theme| theme.style());
+                            let mask_shape = options
+                                .iter()
+                                .find(|cdo| cdo.name == "shape")
+                                .and_then(|cdo| match &cdo.value {
+                                    CommandOptionValue::String(x) => x
+                                        .parse::<gen_pic::MaskShape>()
+                                        .map_err(|e| error!("{}", e))
+                                        .ok(),
+                                    _ => {
+                                        error!("Should get string for shape but instead got something else");
+                                        None
+                                    }
+                                })
+                                .unwrap_or(base.mask_shape);
+                            let layout = options
+                                .iter()
+                                .find(|cdo| cdo.name == "layout")
+                                .and_then(|cdo| match &cdo.value {
+                                    CommandOptionValue::String(x) => x
+                                        .parse::<gen_pic::Layout>()
+                                        .map_err(|e| error!("{}", e))
+                                        .ok(),
+                                    _ => {
+                                        error!("Should get string for layout but instead got something else");
+                                        None
+                                    }
+                                })
+                                .unwrap_or(base.layout);
+                            // encode automatically to fit the upload limit unless given
+                            let output_format = options
+                                .iter()
+                                .find(|cdo| cdo.name == "format")
+                                .and_then(|cdo| match &cdo.value {
+                                    CommandOptionValue::String(x) if x == "auto" => None,
+                                    CommandOptionValue::String(x) => x
+                                        .parse::<gen_pic::OutputFormat>()
+                                        .map_err(|e| error!("{}", e))
+                                        .ok(),
+                                    _ => {
+                                        error!("Should get string for format but instead got something else");
+                                        None
+                                    }
+                                });
+                            // ring each avatar in the color of the member's name, or in one accent color
+                            let rings = options
+                                .iter()
+                                .find(|cdo| cdo.name == "ring")
+                                .and_then(|cdo| match &cdo.value {
+                                    CommandOptionValue::String(x) if x == "role" => Some(
+                                        v_m.iter()
+                                            .map(|m| {
+                                                order::display_color(m, |role_id| {
+                                                    cache
+                                                        .role(role_id)
+                                                        .map(|role| role.resource().clone())
+                                                })
+                                                .unwrap_or(style.text_color)
+                                            })
+                                            .collect(),
+                                    ),
+                                    CommandOptionValue::String(x) => gen_pic::parse_color(x)
+                                        .map(|color| vec![color; v_m.len()])
+                                        .map_err(|e| error!("{}", e))
+                                        .ok(),
+                                    _ => {
+                                        error!("Should get string for ring but instead got something else");
+                                        None
+                                    }
+                                })
+                                .or_else(|| base.rings.take());
+                            let background_option = options
+                                .iter()
+                                .find(|cdo| cdo.name == "background")
+                                .and_then(|cdo| match &cdo.value {
+                                    CommandOptionValue::String(x) => x
+                                        .parse::<BackgroundSource>()
+                                        .map_err(|e| error!("{}", e))
+                                        .ok(),
+                                    _ => {
+                                        error!("Should get string for background but instead got something else");
+                                        None
+                                    }
+                                });
+                            // the background of the template comes before the uploaded one
+                            if background_option.is_some() {
+                                base.background = None;
+                            }
+                            let background_source = background_option.unwrap_or_else(|| {
+                                match guild_defaults.background(gi) {
+                                    Some(_) if base.background.is_none() => {
+                                        BackgroundSource::Upload
+                                    }
+                                    _ => BackgroundSource::None,
+                                }
+                            });
+                            // banners and splashes are 16:9, and covered at most twice as wide
+                            let background_bytes = async {
+                                let url = match background_source {
+                                    BackgroundSource::None | BackgroundSource::Upload => None,
+                                    BackgroundSource::Banner => cache.guild(gi).and_then(|g| {
+                                        g.banner().map(|h| {
+                                            cdn::get_guild_banner(gi, h, cdn::PJWG::PNG, Some(2048))
+                                        })
+                                    }),
+                                    BackgroundSource::Splash => cache.guild(gi).and_then(|g| {
+                                        g.splash().map(|h| {
+                                            cdn::get_guild_splash(gi, h, cdn::PJWG::PNG, Some(2048))
+                                        })
+                                    }),
+                                    BackgroundSource::EventCover => hc
+                                        .guild_scheduled_events(gi)
+                                        .exec()
+                                        .await?
+                                        .models()
+                                        .await?
+                                        .into_iter()
+                                        .find(|e| {
+                                            e.channel_id == Some(ci) && e.status == Status::Active
+                                        })
+                                        .and_then(|e| {
+                                            e.image.map(|h| {
+                                                cdn::get_guild_scheduled_event_cover(
+                                                    e.id,
+                                                    h,
+                                                    cdn::PJWG::PNG,
+                                                    Some(2048),
+                                                )
+                                            })
+                                        }),
+                                };
+                                let bytes = match url {
+                                    Some(url) => {
+                                        let uri: hyper::Uri = url.parse()?;
+                                        let res = asset_rc.get(uri).await?;
+                                        if !res.status().is_success() {
+                                            return Err(anyhow::anyhow!(
+                                                "{} answered {}",
+                                                url,
+                                                res.status()
+                                            ));
+                                        }
+                                        Some(hyper::body::to_bytes(res.into_body()).await?.to_vec())
+                                    }
+                                    None if background_source == BackgroundSource::Upload => {
+                                        match guild_defaults.background(gi) {
+                                            Some(path) => Some(tokio::fs::read(path).await?),
+                                            None => None,
+                                        }
+                                    }
+                                    None => None,
+                                };
+                                Result::<_, anyhow::Error>::Ok(bytes)
+                            };
+                            // without a background the picture keeps the flat one of the style
+                            let background_bytes = match background_bytes.await {
+                                Ok(bytes) => {
+                                    if background_source != BackgroundSource::None
+                                        && bytes.is_none()
+                                    {
+                                        error!(
+                                            "Guild {} has no {} to use as background",
+                                            gi,
+                                            background_source.as_ref()
+                                        );
+                                    }
+                                    bytes
+                                }
+                                Err(e) => {
+                                    error!(
+                                        "Failed to get the {} of guild {} as background: {}",
+                                        background_source.as_ref(),
+                                        gi,
+                                        e
+                                    );
+                                    None
+                                }
+                            };
+                            let text_effect = options
+                                .iter()
+                                .find(|cdo| cdo.name == "text-effect")
+                                .and_then(|cdo| match &cdo.value {
+                                    CommandOptionValue::String(x) => x
+                                        .parse::<gen_pic::TextEffect>()
+                                        .map_err(|e| error!("{}", e))
+                                        .ok(),
+                                    _ => {
+                                        error!("Should get string for text-effect but instead got something else");
+                                        None
+                                    }
+                                })
+                                .or_else(|| {
+                                    let has_background =
+                                        background_bytes.is_some() || base.background.is_some();
+                                    let has_effect = style.text_stroke.is_some()
+                                        || style.text_shadow.is_some()
+                                        || style.header_bar.is_some();
+                                    (has_background && !has_effect)
+                                        .then_some(gen_pic::TextEffect::Shadow)
+                                });
+                            let style = match text_effect {
+                                Some(text_effect) => style.with_text_effect(text_effect),
+                                None => style,
+                            };
+                            let subtitle = subtitle.or_else(|| base.subtitle.take());
+                            let mut config = gen_pic::GroupPicConfig {
+                                header_icon,
+                                subtitle,
+                                layout,
+                                num_of_avatars_in_a_row: column_count,
+                                tile_size,
+                                captions,
+                                badges,
+                                rings,
+                                sections,
+                                style,
+                                mask_shape,
+                                transparent,
+                                ..base
+                            };
+                            let rendered = spawn_blocking(move || {
+                                if let Some(bytes) = background_bytes {
+                                    match image::load_from_memory(&bytes) {
+                                        Ok(image) => {
+                                            config.background =
+                                                Some(gen_pic::Background::new(image.into_rgba8()))
+                                        }
+                                        Err(e) => error!("Failed to decode the background: {}", e),
+                                    }
+                                }
+                                // decode on every core, keeping the order of the members
+                                let avatars = avatars_bytes
+                                    .par_iter()
+                                    .map(|bytes| gen_pic::decode_avatar_frames(bytes))
+                                    .collect::<Result<Vec<_>, gen_pic::GenPicError>>()?;
+                                if animated && avatars.iter().any(|frames| frames.len() > 1) {
+                                    match gen_pic::render_animated_group_pic(
+                                        &avatars,
+                                        &config,
+                                        &gen_pic::AnimationBudget::default(),
+                                    ) {
+                                        Ok(gif) => return Ok(("groupic.gif".to_owned(), gif)),
+                                        // fall back to the first frames
+                                        Err(e @ gen_pic::GenPicError::OverBudget { .. }) => {
+                                            error!("{}", e)
+                                        }
+                                        Err(e) => return Err(e),
+                                    }
+                                }
+                                let avatars: Vec<_> = avatars
+                                    .into_iter()
+                                    .map(|frames| frames.into_iter().next().unwrap().into_buffer())
+                                    .collect();
+                                let group_pic = gen_pic::render_group_pic(&avatars, &config)?;
+                                let (format, bytes) = match output_format {
+                                    Some(format) => {
+                                        (format, gen_pic::encode_group_pic(&group_pic, format)?)
+                                    }
+                                    None => gen_pic::encode_group_pic_within_budget(
+                                        &group_pic,
+                                        gen_pic::DISCORD_UPLOAD_LIMIT,
+                                    )?,
+                                };
+                                Ok((format!("groupic.{}", format.extension()), bytes))
                             })
                             .await?;
-                            dbg_debug!(&groupic_path.is_file());
+                            // a bad avatar or config fails this command only, not the bot
+                            let (groupic_name, groupic_bytes) = match rendered {
+                                Ok(rendered) => rendered,
+                                Err(e) => {
+                                    error!(
+                                        "Failed to render the group picture of {}: {}",
+                                        vc.name, e
+                                    );
+                                    let content =
+                                        format!("Failed to draw the group picture: {}", e);
+                                    ic.update_interaction_original(&ac.token)
+                                        .content(Some(&content))?
+                                        .exec()
+                                        .await?;
+                                    continue;
+                                }
+                            };
+                            dbg_debug!(groupic_bytes.len());
 
                             // let content = vc.name
                             //     + "\n"
@@ -400,22 +1103,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                             //         .collect::<Vec<_>>()
                             //         .join("\n");
                             let content = "Oats curry everyone!".to_owned();
-                            let cbd = twilight_util::builder::CallbackDataBuilder::new()
-                                .content(content)
-                                // .flags(MessageFlags::EPHEMERAL)
-                                .build();
-                            ic.create_interaction_original(
-                                ac.id,
-                                &ac.token,
-                                &InteractionResponse::ChannelMessageWithSource(cbd),
-                            )
-                            .exec()
-                            .await?;
-                            let groupic_bytes = fs::read(groupic_path)
-                                .await
-                                .with_context(|| "Failed to read groupic.png")?;
-                            let af = AttachmentFile::from_bytes("groupic.png", &groupic_bytes);
+                            let af = AttachmentFile::from_bytes(&groupic_name, &groupic_bytes);
                             ic.update_interaction_original(&ac.token)
+                                .content(Some(&content))?
                                 .attach(&[af])
                                 .exec()
                                 .await?;
@@ -0,0 +1,448 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::HashMap,
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use feruca::Collator;
+use groupic::gen_pic::AvatarSection;
+use image::Rgba;
+use twilight_model::guild::{Member, Role};
+use twilight_model::id::{
+    marker::{ChannelMarker, RoleMarker, UserMarker},
+    Id,
+};
+use twilight_model::voice::VoiceState;
+
+type ChannelId = Id<ChannelMarker>;
+type RoleId = Id<RoleMarker>;
+type UserId = Id<UserMarker>;
+
+/// Order of the avatars in the group picture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum AvatarOrder {
+    /// Whoever joined the voice channel first comes first
+    #[default]
+    JoinOrder,
+    /// Alphabetical by display name, in the root collation order of Unicode
+    DisplayName,
+    /// Highest role first
+    RolePosition,
+    /// Oldest account first
+    AccountId,
+}
+
+impl AvatarOrder {
+    pub const ALL: [AvatarOrder; 4] = [
+        AvatarOrder::JoinOrder,
+        AvatarOrder::DisplayName,
+        AvatarOrder::RolePosition,
+        AvatarOrder::AccountId,
+    ];
+}
+
+impl AsRef<str> for AvatarOrder {
+    fn as_ref(&self) -> &'static str {
+        match self {
+            Self::JoinOrder => "join-order",
+            Self::DisplayName => "name",
+            Self::RolePosition => "role",
+            Self::AccountId => "id",
+        }
+    }
+}
+
+impl FromStr for AvatarOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|order| order.as_ref() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("unknown avatar order {}", s))
+    }
+}
+
+/// Order in which users joined their voice channels, as seen on the gateway
+#[derive(Debug, Default)]
+pub struct JoinOrder {
+    next: u64,
+    joined: HashMap<UserId, (ChannelId, u64)>,
+}
+
+impl JoinOrder {
+    /// Record a voice state update, where a user joins whenever their channel changes
+    pub fn update(&mut self, vs: &VoiceState) {
+        match vs.channel_id {
+            Some(ci) => {
+                if self.joined.get(&vs.user_id).map(|&(c, _)| c) != Some(ci) {
+                    self.joined.insert(vs.user_id, (ci, self.next));
+                    self.next += 1;
+                }
+            }
+            None => {
+                self.joined.remove(&vs.user_id);
+            }
+        }
+    }
+
+    /// When the user joined their channel, or `None` if they were in it before the bot connected
+    fn joined(&self, user_id: UserId) -> Option<u64> {
+        self.joined.get(&user_id).map(|&(_, seq)| seq)
+    }
+}
+
+/// Sort the members of a voice channel together with their data, with `pinned` first
+///
+/// `role_position` looks up the position of a role in the guild. Ties are broken by account id,
+/// so that retakes come out the same.
+pub fn sort_members<T>(
+    members: &mut [(Member, T)],
+    order: AvatarOrder,
+    pinned: Option<UserId>,
+    join_order: &JoinOrder,
+    role_position: impl Fn(RoleId) -> Option<i64>,
+) {
+    let top_role = |m: &Member| m.roles.iter().filter_map(|&r| role_position(r)).max();
+    let mut collator = Collator::default();
+    members.sort_by(|(a, _), (b, _)| {
+        let is_pinned = |m: &Member| Some(m.user.id) == pinned;
+        let by_order = match order {
+            // members who were there before the bot connected go first
+            AvatarOrder::JoinOrder => join_order
+                .joined(a.user.id)
+                .cmp(&join_order.joined(b.user.id)),
+            AvatarOrder::DisplayName => collator.collate(display_name(a), display_name(b)),
+            AvatarOrder::RolePosition => top_role(b).cmp(&top_role(a)),
+            AvatarOrder::AccountId => Ordering::Equal,
+        };
+        is_pinned(b)
+            .cmp(&is_pinned(a))
+            .then(by_order)
+            .then(a.user.id.get().cmp(&b.user.id.get()))
+    });
+}
+
+/// Group the sorted members into sections by their highest hoisted role, like the member list
+///
+/// Sections are ordered by role position and keep the order of the members within them. Members
+/// without a hoisted role come last. `role` looks up a role in the guild.
+pub fn group_by_hoisted_role<T>(
+    members: &mut [(Member, T)],
+    role: impl Fn(RoleId) -> Option<Role>,
+) -> Vec<AvatarSection> {
+    let hoisted_role = |m: &Member| {
+        m.roles
+            .iter()
+            .filter_map(|&r| role(r))
+            .filter(|r| r.hoist)
+            .max_by_key(|r| (r.position, Reverse(r.id.get())))
+    };
+    // the sort is stable
+    members.sort_by_cached_key(|(m, _)| Reverse(hoisted_role(m).map(|r| (r.position, r.id.get()))));
+
+    let mut sections: Vec<(Option<RoleId>, AvatarSection)> = vec![];
+    for (m, _) in members.iter() {
+        let r = hoisted_role(m);
+        let id = r.as_ref().map(|r| r.id);
+        match sections.last_mut() {
+            Some((last_id, section)) if *last_id == id => section.count += 1,
+            _ => sections.push((
+                id,
+                AvatarSection {
+                    title: r.as_ref().map_or("Members", |r| &r.name).to_owned(),
+                    color: r.filter(|r| r.color != 0).map(|r| role_color(r.color)),
+                    count: 1,
+                },
+            )),
+        }
+    }
+    sections.into_iter().map(|(_, section)| section).collect()
+}
+
+/// Color of the member's name, taken from their highest colored role, or `None` if no role has a
+/// color
+pub fn display_color(m: &Member, role: impl Fn(RoleId) -> Option<Role>) -> Option<Rgba<u8>> {
+    m.roles
+        .iter()
+        .filter_map(|&r| role(r))
+        .filter(|r| r.color != 0)
+        .max_by_key(|r| (r.position, Reverse(r.id.get())))
+        .map(|r| role_color(r.color))
+}
+
+/// Convert a role color in `0xRRGGBB` to a pixel
+fn role_color(color: u32) -> Rgba<u8> {
+    let [_, r, g, b] = color.to_be_bytes();
+    Rgba([r, g, b, 255])
+}
+
+fn display_name(m: &Member) -> &str {
+    m.nick.as_deref().unwrap_or(&m.user.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use twilight_model::datetime::Timestamp;
+    use twilight_model::guild::Permissions;
+    use twilight_model::user::User;
+
+    fn member(id: u64, nick: &str, roles: &[u64]) -> Member {
+        Member {
+            avatar: None,
+            communication_disabled_until: None,
+            deaf: false,
+            guild_id: Id::new(1),
+            joined_at: Timestamp::from_secs(1_600_000_000).unwrap(),
+            mute: false,
+            nick: Some(nick.to_owned()),
+            pending: false,
+            premium_since: None,
+            roles: roles.iter().map(|&r| Id::new(r)).collect(),
+            user: User {
+                accent_color: None,
+                avatar: None,
+                banner: None,
+                bot: false,
+                discriminator: 1,
+                email: None,
+                flags: None,
+                id: Id::new(id),
+                locale: None,
+                mfa_enabled: None,
+                name: format!("user{}", id),
+                premium_type: None,
+                public_flags: None,
+                system: None,
+                verified: None,
+            },
+        }
+    }
+
+    fn role(id: u64, name: &str, position: i64, hoist: bool, color: u32) -> Role {
+        Role {
+            color,
+            hoist,
+            icon: None,
+            id: Id::new(id),
+            managed: false,
+            mentionable: false,
+            name: name.to_owned(),
+            permissions: Permissions::empty(),
+            position,
+            tags: None,
+            unicode_emoji: None,
+        }
+    }
+
+    fn voice_state(user_id: u64, channel_id: Option<u64>) -> VoiceState {
+        VoiceState {
+            channel_id: channel_id.map(Id::new),
+            deaf: false,
+            guild_id: Some(Id::new(1)),
+            member: None,
+            mute: false,
+            self_deaf: false,
+            self_mute: false,
+            self_stream: false,
+            self_video: false,
+            session_id: String::new(),
+            suppress: false,
+            token: None,
+            user_id: Id::new(user_id),
+            request_to_speak_timestamp: None,
+        }
+    }
+
+    /// Ids of `members` in the order `sort_members` puts them in
+    fn sorted(
+        members: &[Member],
+        order: AvatarOrder,
+        pinned: Option<u64>,
+        join_order: &JoinOrder,
+        roles: &[Role],
+    ) -> Vec<u64> {
+        let mut members: Vec<_> = members.iter().map(|m| (m.clone(), ())).collect();
+        sort_members(&mut members, order, pinned.map(Id::new), join_order, |id| {
+            roles.iter().find(|r| r.id == id).map(|r| r.position)
+        });
+        members.iter().map(|(m, _)| m.user.id.get()).collect()
+    }
+
+    #[test]
+    fn host_comes_first() {
+        let members: Vec<_> = (1..=4).map(|id| member(id, "Ayumu", &[])).collect();
+        let join_order = JoinOrder::default();
+        for order in AvatarOrder::ALL {
+            assert_eq!(
+                sorted(&members, order, Some(3), &join_order, &[]),
+                [3, 1, 2, 4]
+            );
+        }
+    }
+
+    #[test]
+    fn join_order_after_members_already_there() {
+        let members: Vec<_> = (1..=4).map(|id| member(id, "Ayumu", &[])).collect();
+        let mut join_order = JoinOrder::default();
+        join_order.update(&voice_state(4, Some(10)));
+        join_order.update(&voice_state(2, Some(10)));
+        let order = AvatarOrder::JoinOrder;
+        assert_eq!(
+            sorted(&members, order, None, &join_order, &[]),
+            [1, 3, 4, 2]
+        );
+
+        // leaving and coming back counts as joining again
+        join_order.update(&voice_state(4, None));
+        join_order.update(&voice_state(4, Some(10)));
+        assert_eq!(
+            sorted(&members, order, None, &join_order, &[]),
+            [1, 3, 2, 4]
+        );
+        // so does moving to another channel, but not a repeated update of the same channel
+        join_order.update(&voice_state(2, Some(11)));
+        join_order.update(&voice_state(4, Some(10)));
+        assert_eq!(
+            sorted(&members, order, None, &join_order, &[]),
+            [1, 3, 4, 2]
+        );
+    }
+
+    #[test]
+    fn highest_role_first() {
+        let roles = [
+            role(10, "Audience", 1, false, 0),
+            role(11, "Staff", 5, false, 0),
+            role(12, "Performer", 3, false, 0),
+        ];
+        let members = [
+            member(1, "Ayumu", &[10]),
+            member(2, "Kasumi", &[11]),
+            member(3, "Shizuku", &[]),
+            member(4, "Karin", &[10, 12]),
+            member(5, "Ai", &[11]),
+        ];
+        assert_eq!(
+            sorted(
+                &members,
+                AvatarOrder::RolePosition,
+                None,
+                &JoinOrder::default(),
+                &roles
+            ),
+            [2, 5, 4, 1, 3]
+        );
+    }
+
+    #[test]
+    fn ties_broken_by_account_id() {
+        let members = [
+            member(3, "Ayumu", &[]),
+            member(1, "Ayumu", &[]),
+            member(4, "Kasumi", &[]),
+            member(2, "Ayumu", &[]),
+        ];
+        let join_order = JoinOrder::default();
+        assert_eq!(
+            sorted(&members, AvatarOrder::DisplayName, None, &join_order, &[]),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(
+            sorted(&members, AvatarOrder::JoinOrder, None, &join_order, &[]),
+            [1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn names_ignore_accents_and_case() {
+        let mut collator = Collator::default();
+        let mut names = vec![
+            "Émma", "rina", "Ayumu", "emma", "Åyumu", "Kasumi", "Zoë", "Zoe",
+        ];
+        names.sort_by(|a, b| collator.collate(*a, *b));
+        assert_eq!(
+            names,
+            vec!["Ayumu", "Åyumu", "emma", "Émma", "Kasumi", "rina", "Zoe", "Zoë"]
+        );
+    }
+
+    #[test]
+    fn sections_by_hoisted_role() {
+        let roles = [
+            role(20, "Staff", 10, true, 0xe91e63),
+            role(21, "Performers", 5, true, 0),
+            role(22, "Regulars", 8, false, 0x3498db),
+        ];
+        let mut members: Vec<_> = vec![
+            member(1, "Ayumu", &[21]),
+            member(2, "Kasumi", &[22]),
+            member(3, "Shizuku", &[20, 21]),
+            member(4, "Karin", &[21]),
+            member(5, "Ai", &[20]),
+            member(6, "Kanata", &[]),
+        ]
+        .into_iter()
+        .map(|m| (m, ()))
+        .collect();
+        let sections = group_by_hoisted_role(&mut members, |id| {
+            roles.iter().find(|r| r.id == id).cloned()
+        });
+        assert_eq!(
+            sections,
+            [
+                AvatarSection {
+                    title: "Staff".into(),
+                    color: Some(Rgba([0xe9, 0x1e, 0x63, 255])),
+                    count: 2,
+                },
+                AvatarSection {
+                    title: "Performers".into(),
+                    color: None,
+                    count: 2,
+                },
+                AvatarSection {
+                    title: "Members".into(),
+                    color: None,
+                    count: 2,
+                },
+            ]
+        );
+        // members keep their order within a section
+        let ids: Vec<_> = members.iter().map(|(m, _)| m.user.id.get()).collect();
+        assert_eq!(ids, [3, 5, 1, 4, 2, 6]);
+    }
+
+    #[test]
+    fn highest_colored_role_colors_the_name() {
+        let roles = [
+            role(20, "Staff", 10, true, 0xe91e63),
+            role(21, "Performers", 5, true, 0),
+            role(22, "Regulars", 8, false, 0x3498db),
+            role(23, "Bots", 12, false, 0),
+        ];
+        let role = |id| roles.iter().find(|r: &&Role| r.id == id).cloned();
+        let color = |roles: &[u64]| display_color(&member(1, "Ayumu", roles), role);
+        assert_eq!(color(&[22, 20, 21]), Some(Rgba([0xe9, 0x1e, 0x63, 255])));
+        // roles without a color are passed over
+        assert_eq!(color(&[23, 22]), Some(Rgba([0x34, 0x98, 0xdb, 255])));
+        assert_eq!(color(&[21, 23]), None);
+        assert_eq!(color(&[]), None);
+    }
+
+    #[test]
+    fn role_colors() {
+        assert_eq!(role_color(0x5865f2), Rgba([0x58, 0x65, 0xf2, 255]));
+    }
+
+    #[test]
+    fn parse_avatar_order() {
+        for order in AvatarOrder::ALL {
+            assert_eq!(order.as_ref().parse::<AvatarOrder>().unwrap(), order);
+        }
+        assert!("height".parse::<AvatarOrder>().is_err());
+    }
+}
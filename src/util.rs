@@ -32,9 +32,32 @@ pub async fn delete_guild_commands(client: &InteractionClient<'_>, guild_id: Gui
     Ok(())
 }
 
+/// Milliseconds since the Unix epoch of the first second of 2015, where snowflakes count from
+const DISCORD_EPOCH_MS: u64 = 1_420_070_400_000;
+
+/// Format the UTC date a snowflake id was created on as `YYYY-MM-DD`
+pub fn snowflake_date(id: u64) -> String {
+    let days = (((id >> 22) + DISCORD_EPOCH_MS) / 86_400_000) as i64;
+    // days to the civil calendar, counting in 400 year eras starting on March 1st
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
 pub mod cdn {
-    use twilight_model::id::{Id, marker::{GuildMarker, UserMarker}};
+    use twilight_model::id::{
+        marker::{GuildMarker, ScheduledEventMarker, UserMarker},
+        Id,
+    };
     type GuildId = Id<GuildMarker>;
+    type ScheduledEventId = Id<ScheduledEventMarker>;
     type UserId = Id<UserMarker>;
 
     use std::fmt::Display;
@@ -67,6 +90,20 @@ pub mod cdn {
         };
     }
 
+    /// Append the requested image size to path if any, which must be a power of two between 16
+    /// and 4096
+    macro_rules! sized {
+        ($path:expr, $size:expr) => {
+            match $size {
+                Some(size) => {
+                    debug_assert!(size.is_power_of_two() && (16..=4096).contains(&size));
+                    format!("{}?size={}", $path, size)
+                }
+                None => $path,
+            }
+        };
+    }
+
     /// Get path to default user avatar by user discriminator
     macro_rules! default_user_avatar {
         ($user_discriminator:expr) => {
@@ -91,18 +128,50 @@ pub mod cdn {
         };
     }
 
-    pub fn get_default_user_avatar(discriminator: u16) -> String {
-        base!(default_user_avatar!(discriminator))
+    /// Get path to guild icon by guild id, guild icon hash, and image format
+    macro_rules! guild_icon {
+        ($guild_id:expr, $guild_icon:expr, $format:expr) => {
+            format!("icons/{}/{}.{}", $guild_id, $guild_icon, $format)
+        };
+    }
+
+    /// Get path to guild banner by guild id, guild banner hash, and image format
+    macro_rules! guild_banner {
+        ($guild_id:expr, $guild_banner:expr, $format:expr) => {
+            format!("banners/{}/{}.{}", $guild_id, $guild_banner, $format)
+        };
+    }
+
+    /// Get path to guild invite splash by guild id, guild splash hash, and image format
+    macro_rules! guild_splash {
+        ($guild_id:expr, $guild_splash:expr, $format:expr) => {
+            format!("splashes/{}/{}.{}", $guild_id, $guild_splash, $format)
+        };
     }
 
-    pub fn get_user_avatar<S>(user_id: UserId, user_avatar: S, format: PJWG) -> String
+    /// Get path to scheduled event cover by event id, cover image hash, and image format
+    macro_rules! guild_scheduled_event_cover {
+        ($event_id:expr, $event_cover:expr, $format:expr) => {
+            format!("guild-events/{}/{}.{}", $event_id, $event_cover, $format)
+        };
+    }
+
+    pub fn get_default_user_avatar(discriminator: u16, size: Option<u32>) -> String {
+        base!(sized!(default_user_avatar!(discriminator), size))
+    }
+
+    pub fn get_user_avatar<S>(
+        user_id: UserId,
+        user_avatar: S,
+        format: PJWG,
+        size: Option<u32>,
+    ) -> String
     where
         S: Display,
     {
-        base!(user_avatar!(
-            user_id,
-            user_avatar.to_string(),
-            format.as_ref()
+        base!(sized!(
+            user_avatar!(user_id, user_avatar.to_string(), format.as_ref()),
+            size
         ))
     }
 
@@ -111,15 +180,92 @@ pub mod cdn {
         user_id: UserId,
         member_avatar: S,
         format: PJWG,
+        size: Option<u32>,
+    ) -> String
+    where
+        S: Display,
+    {
+        base!(sized!(
+            guild_member_avatar!(
+                guild_id,
+                user_id,
+                member_avatar.to_string(),
+                format.as_ref()
+            ),
+            size
+        ))
+    }
+
+    pub fn get_guild_icon<S>(
+        guild_id: GuildId,
+        guild_icon: S,
+        format: PJWG,
+        size: Option<u32>,
     ) -> String
     where
         S: Display,
     {
-        base!(guild_member_avatar!(
-            guild_id,
-            user_id,
-            member_avatar.to_string(),
-            format.as_ref()
+        base!(sized!(
+            guild_icon!(guild_id, guild_icon.to_string(), format.as_ref()),
+            size
         ))
     }
+
+    pub fn get_guild_banner<S>(
+        guild_id: GuildId,
+        guild_banner: S,
+        format: PJWG,
+        size: Option<u32>,
+    ) -> String
+    where
+        S: Display,
+    {
+        base!(sized!(
+            guild_banner!(guild_id, guild_banner.to_string(), format.as_ref()),
+            size
+        ))
+    }
+
+    pub fn get_guild_splash<S>(
+        guild_id: GuildId,
+        guild_splash: S,
+        format: PJWG,
+        size: Option<u32>,
+    ) -> String
+    where
+        S: Display,
+    {
+        base!(sized!(
+            guild_splash!(guild_id, guild_splash.to_string(), format.as_ref()),
+            size
+        ))
+    }
+
+    pub fn get_guild_scheduled_event_cover<S>(
+        event_id: ScheduledEventId,
+        event_cover: S,
+        format: PJWG,
+        size: Option<u32>,
+    ) -> String
+    where
+        S: Display,
+    {
+        base!(sized!(
+            guild_scheduled_event_cover!(event_id, event_cover.to_string(), format.as_ref()),
+            size
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snowflake_dates() {
+        assert_eq!(snowflake_date(0), "2015-01-01");
+        assert_eq!(snowflake_date(175928847299117063), "2016-04-30");
+        let leap_day = (1_582_934_400_000 - DISCORD_EPOCH_MS) << 22;
+        assert_eq!(snowflake_date(leap_day), "2020-02-29");
+    }
 }